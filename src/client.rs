@@ -1,14 +1,19 @@
-use std::io;
 use error::RbtError;
-use amqp::{self, Session, Options, Channel};
+use amqp::{self, AMQPError, Session, Options, Channel};
 use amqp::protocol::basic::{Deliver, BasicProperties};
 use amqp::Basic;
 use amqp::{Table, TableEntry};
+use amqp::protocol;
+use amq_proto::Method;
+use ctrlc;
 
 use std::thread;
-use std::time::Duration;
-use std::sync::mpsc;
-use std::error::Error;
+use std::time::{Duration, Instant};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::HashMap;
+use std::cmp;
+use rand::{thread_rng, Rng, distributions::Alphanumeric};
 
 pub struct Sendable {
     pub exchange: String,
@@ -16,112 +21,502 @@ pub struct Sendable {
     pub content_type: String,
     pub headers: Vec<String>,
     pub file_name: String,
-    pub reader: Box<io::Read>,
+    pub body: Vec<u8>,
     pub priority: u8,
-    pub rpctimeout: u64
+    pub rpctimeout: u64,
+    pub declare: Option<ExchangeDeclare>,
+    pub direct_reply: bool,
+}
+
+// how to declare the exchange before publishing. without a
+// type, we only (passively) check that the exchange exists.
+pub struct ExchangeDeclare {
+    pub exchange_type: Option<String>,
+    pub durable: bool,
+    pub auto_delete: bool,
 }
 
 pub type ReceiveCb = FnMut(&mut Channel, Deliver, BasicProperties, Vec<u8>) -> Result<(), RbtError> + Send;
+pub type FilterCb = Fn(&Deliver, &BasicProperties, &[u8]) -> bool + Send;
 
-pub struct Receiver {
-    pub exchange:String,
+// what to do with a message the callback failed on
+pub enum OnError {
+    // nack it back to the queue and exit
+    Exit,
+    // ack it, it's gone
+    Skip,
+    // nack without requeue, the queue's dead letter exchange gets it, if any
+    Nack,
+    // nack and put it back on the queue
+    Requeue,
+    // publish it to this exchange with the error in a header, and ack.
+    // without an exchange, the same as Nack.
+    DeadLetter(Option<String>),
+}
+
+// messages the callback failed on, delivery tag and error
+pub type Failures = Arc<Mutex<Vec<(u64, String)>>>;
+
+// how to declare a named queue. anonymous queues are always
+// exclusive and auto-deleted.
+#[derive(Default)]
+pub struct QueueDeclare {
+    pub durable: bool,
+    pub exclusive: bool,
+    pub auto_delete: bool,
+    // x-queue-type, x-message-ttl and such
+    pub arguments: Table,
+}
+
+// an exchange to bind the queue to. without a routing key, an
+// anonymous queue is bound with # and a named queue isn't bound,
+// unless there are binding arguments.
+pub struct Binding {
+    pub exchange: String,
     pub routing_key: Option<String>,
+    // such as x-match and the headers to match for a headers exchange
+    pub arguments: Table,
+}
+
+impl Binding {
+    // the routing key to bind with, if any. a headers exchange
+    // binding has arguments and doesn't care about the key.
+    pub fn bind_key(&self, default:Option<&String>) -> Option<String> {
+        match self.routing_key {
+            Some(ref k) => Some(k.clone()),
+            None if !self.arguments.is_empty() => Some("".to_owned()),
+            None => default.cloned(),
+        }
+    }
+}
+
+pub struct Receiver {
+    pub bindings: Vec<Binding>,
+    // ack once the callback is done with it
     pub auto_ack: bool,
+    pub on_error: OnError,
+    pub failures: Failures,
+    // prefetch count, and whether it's for the whole channel
+    pub prefetch: Option<(u16, bool)>,
+    // consumer arguments, such as x-stream-offset
+    pub arguments: Table,
+    // only deliver messages with this correlation_id (rpc replies)
+    pub correlation_id: Option<String>,
+    // only deliver messages it lets through, the rest are acked
+    pub filter: Option<Box<FilterCb>>,
     pub callback:Box<ReceiveCb>,
 }
 
 
-pub fn open_send(o:Options, s:Sendable, r:Option<Receiver>) -> Result<(),RbtError> {
+pub fn open_send(o:Options, s:Sendable) -> Result<(),RbtError> {
+
+    let vhost = vhost_name(&o);
 
     // open the channel
     let (mut session, mut channel) = _open(o)?;
 
-    // table of headers, parsed from input
-    let mut headers = Table::new();
-    for st in s.headers {
-        let idx = st.find(':').ok_or("Header must have a :")?;
-        let (name, value) = st.split_at(idx);
-        let key = name.trim();
-        let valstr = (&value[1..]).trim();
-        let val = narrow(valstr);
-        headers.insert(String::from(key), val);
+    // make sure the exchange is there
+    if let Some(ref declare) = s.declare {
+        declare_exchange(&mut channel, &vhost, &s.exchange, declare)?;
     }
 
-    // put filename in headers if we read from file
-    if s.file_name != "-" && !headers.contains_key("fileName") {
-        headers.insert("fileName".to_owned(), TableEntry::LongString(String::from(s.file_name)));
+    // send properties
+    let props = build_props(&s)?;
+
+    // publish it
+    channel.basic_publish(s.exchange, s.routing_key, false, false, props, s.body)?;
+
+    // and unwind
+    channel.close(200, "Bye")?;
+    session.close(200, "Good Bye");
+
+    Ok(())
+}
+
+
+// publish the sendable as an rpc request and hand each reply to on_reply,
+// until want replies arrived (all within the rpctimeout when None).
+// when no reply arrives within the rpctimeout, the request is published
+// again up to retries times, waiting backoff (doubling) between attempts.
+// a late reply to an earlier attempt is as good as any.
+pub fn open_rpc<F>(o:Options, s:Sendable, want:Option<usize>, retries:u32, backoff:u64,
+                   mut on_reply:F) -> Result<(),RbtError>
+    where F: FnMut(Deliver, BasicProperties, Vec<u8>) -> Result<(),RbtError> {
+
+    let vhost = vhost_name(&o);
+
+    let (mut session, channel) = _open(o)?;
+    let mut channel = Some(channel);
+
+    // replies from all attempts end up here, None on ctrl-c
+    let (tx, rx) = mpsc::channel();
+
+    // stop waiting on ctrl-c or kill, and don't retry
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let tx = tx.clone();
+        let interrupted = interrupted.clone();
+        on_signal(move || {
+            interrupted.store(true, Ordering::SeqCst);
+            let _ = tx.send(None);
+        })?;
     }
 
-    // send properties
-    let mut props = BasicProperties {
-        content_type: Some(s.content_type),
-        headers: Some(headers),
-        priority: Some(s.priority),
-        ..Default::default()
+    let timeout = match s.rpctimeout {
+        0 => None,
+        t => Some(Duration::from_millis(t)),
     };
+    let mut backoff = Duration::from_millis(backoff);
+    let mut got = 0;
 
-    // if we are doing rpc, there is a receiver in this optional
-    let isrpc = match r {
-        Some(receiver) => {
-            // open a receiver and get the queue name
-            let queue_name = do_open_receive(&mut channel, None, false, receiver)?;
+    // channel id and consumer tag of each attempt
+    let mut consumers = vec![];
 
-            // put queue name as our reply to
-            props.reply_to = Some(queue_name);
+    'attempts: for attempt in 0..(retries + 1) {
 
-            // and a fixed correltionId
-            props.correlation_id = Some("rabbiteer here".to_owned());
+        if interrupted.load(Ordering::SeqCst) {
+            break;
+        }
 
-            true
-        },
-        None => false
-    };
+        // each attempt needs a channel of its own, since the
+        // previous one is stuck consuming.
+        let mut ch = match channel.take() {
+            Some(ch) => ch,
+            None => session.open_channel(attempt as u16 + 1)?,
+        };
 
-    // read input input buffer
-    let mut buffer = Box::new(vec![]);
-    let mut reader = s.reader;
-    reader.read_to_end(&mut buffer)?;
+        // make sure the exchange is there
+        if attempt == 0 {
+            if let Some(ref declare) = s.declare {
+                declare_exchange(&mut ch, &vhost, &s.exchange, declare)?;
+            }
+        }
 
-    // publish it
-    channel.basic_publish(s.exchange, s.routing_key, false, false, props, *buffer)?;
+        // unique per request, so we can tell our reply from others
+        let correlation_id = gen_correlation_id();
+        let tx = tx.clone();
+        let receiver = Receiver {
+            bindings: vec![],
+            auto_ack: true,
+            on_error: OnError::Exit,
+            failures: Failures::default(),
+            prefetch: None,
+            arguments: Table::new(),
+            correlation_id: Some(correlation_id.clone()),
+            filter: None,
+            callback: Box::new(move |_:&mut Channel, deliver:Deliver, props:BasicProperties, body:Vec<u8>| {
+                // a late reply once we're done has nowhere to go
+                let _ = tx.send(Some((deliver, props, body)));
+                Ok(())
+            }),
+        };
+
+        // open a receiver and get the queue name
+        let (queue_name, consumer_tag) = if s.direct_reply {
+            do_open_direct_reply(&mut ch, receiver)?
+        } else {
+            do_open_receive(&mut ch, None, None, receiver)?
+        };
+
+        let mut props = build_props(&s)?;
+        props.reply_to = Some(queue_name);
+        props.correlation_id = Some(correlation_id);
+
+        ch.basic_publish(s.exchange.clone(), s.routing_key.clone(), false, false,
+                         props, s.body.clone())?;
 
-    if isrpc {
-        let (tx, rx) = mpsc::channel();
+        consumers.push((ch.id, consumer_tag));
         thread::Builder::new().name("consumer_thread".to_string()).spawn(move || {
-            channel.start_consuming();
-            tx.send(channel).unwrap();
-        }).unwrap();
+            ch.start_consuming();
+        })?;
 
+        // collect replies until we have what we want, or time is up
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if want.map(|n| got >= n).unwrap_or(false) {
+                break 'attempts;
+            }
 
-        let timeout = s.rpctimeout;
-        if timeout == 0 {
-            // Block forever until recieve
-            rx.recv().unwrap();
-        } else {
-            let res = rx.recv_timeout(Duration::from_millis(timeout));
+            let res = match deadline {
+                None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+                Some(d) => {
+                    let now = Instant::now();
+                    rx.recv_timeout(if d > now { d - now } else { Duration::from_millis(0) })
+                },
+            };
 
             match res {
-                Ok(mut ch) => {
-                    ch.close(200, "Bye")?;
-                }                  
-                Err(err) => {
-                    if err.description() == "timed out waiting on channel".to_string() {
-                        println!("Error timeout");
+                Ok(None) => break 'attempts,
+                Ok(Some((deliver, props, body))) => {
+                    got += 1;
+                    if let Err(e) = on_reply(deliver, props, body) {
+                        session.close(200, "Good Bye");
+                        return Err(e);
                     }
-                }
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    rbterr!("Connection lost while waiting for rpc reply");
+                },
             }
         }
-        session.close(200, "Good Bye");
-    } else {
-        // and unwind if not rpc
-        channel.close(200, "Bye")?;
-        session.close(200, "Good Bye");
+
+        // some replies, but not as many as we wanted
+        if got > 0 {
+            break;
+        }
+
+        if attempt < retries {
+            errln!("No rpc reply within {} ms, retrying in {} ms ({}/{})",
+                   s.rpctimeout, millis(backoff), attempt + 1, retries);
+            thread::sleep(backoff);
+            if backoff < MAX_BACKOFF {
+                backoff = cmp::min(backoff.checked_mul(2).unwrap_or(MAX_BACKOFF), MAX_BACKOFF);
+            }
+        }
+    }
+
+    // the consumers are stuck reading their channels
+    let interrupted = interrupted.load(Ordering::SeqCst);
+    if interrupted {
+        let mut control = session.open_channel(consumers.len() as u16 + 1)?;
+        for (channel_id, consumer_tag) in consumers {
+            cancel_consumer(&mut control, channel_id, consumer_tag)?;
+            close_channel(&mut control, channel_id)?;
+        }
+        control.close(200, "Bye")?;
+    }
+
+    session.close(200, "Good Bye");
+
+    if got == 0 {
+        if interrupted {
+            rbterr!("Interrupted while waiting for rpc reply");
+        }
+        return Err(RbtError::Timeout(s.rpctimeout));
+    }
+
+    // a partial gather is still output, but say so
+    if let Some(n) = want {
+        if got < n {
+            errln!("Only {} of {} rpc replies within {} ms", got, n, s.rpctimeout);
+        }
     }
 
     Ok(())
 }
 
 
+// the doubling backoff between retries stops here
+const MAX_BACKOFF:Duration = Duration::from_secs(60);
+
+
+fn millis(d:Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+
+// for error messages, the empty vhost is the default one
+fn vhost_name(o:&Options) -> String {
+    match o.vhost.as_ref() {
+        "" => "/".to_owned(),
+        v  => v.to_owned(),
+    }
+}
+
+
+// the outcome of one request in a batch
+pub enum BatchReply {
+    Reply(BasicProperties, Vec<u8>),
+    Timeout,
+}
+
+
+// send each of the bodies (with its content type) as its own rpc
+// request, with at most in_flight requests waiting for a reply at a
+// time. on_reply is called with the index of the body as replies
+// arrive or time out. the body of the sendable itself is not sent.
+pub fn open_send_batch<F>(o:Options, s:Sendable, bodies:Vec<(String, Vec<u8>)>,
+                          in_flight:usize, mut on_reply:F) -> Result<(),RbtError>
+    where F: FnMut(usize, &str, BatchReply) -> Result<(),RbtError> {
+
+    let vhost = vhost_name(&o);
+
+    // replies are consumed on one channel and handed to us here,
+    // None on ctrl-c or kill
+    let (mut session, mut channel) = _open(o)?;
+    let (tx, rx) = mpsc::channel();
+    {
+        let tx = tx.clone();
+        on_signal(move || { let _ = tx.send(None); })?;
+    }
+    let receiver = Receiver {
+        bindings: vec![],
+        auto_ack: true,
+        on_error: OnError::Exit,
+        failures: Failures::default(),
+        prefetch: None,
+        arguments: Table::new(),
+        correlation_id: None,
+        filter: None,
+        callback: Box::new(move |_:&mut Channel, _:Deliver, props:BasicProperties, body:Vec<u8>| {
+            // a late or duplicate reply once the batch is done has
+            // nowhere to go, which is fine.
+            let _ = tx.send(Some((props, body)));
+            Ok(())
+        }),
+    };
+    let (queue_name, consumer_tag) = do_open_receive(&mut channel, None, None, receiver)?;
+    let channel_id = channel.id;
+    thread::Builder::new().name("consumer_thread".to_string()).spawn(move || {
+        channel.start_consuming();
+    })?;
+
+    // while we publish on another, which is also where we stop
+    // the consumer from
+    let mut pub_channel = session.open_channel(2)?;
+
+    if let Some(ref declare) = s.declare {
+        declare_exchange(&mut pub_channel, &vhost, &s.exchange, declare)?;
+    }
+
+    let mut props = build_props(&s)?;
+    props.reply_to = Some(queue_name);
+
+    let timeout = match s.rpctimeout {
+        0 => None,
+        t => Some(Duration::from_millis(t)),
+    };
+
+    // correlation id -> (index, time sent)
+    let mut pending:HashMap<String, (usize, Instant)> = HashMap::new();
+    let mut bodies = bodies.into_iter().enumerate();
+    let mut sent_all = false;
+
+    loop {
+
+        // fill up the window
+        while !sent_all && pending.len() < in_flight {
+            match bodies.next() {
+                Some((idx, (content_type, body))) => {
+                    let correlation_id = gen_correlation_id();
+                    let mut p = props.clone();
+                    p.content_type = Some(content_type);
+                    p.correlation_id = Some(correlation_id.clone());
+                    pub_channel.basic_publish(s.exchange.clone(), s.routing_key.clone(),
+                                              false, false, p, body)?;
+                    pending.insert(correlation_id, (idx, Instant::now()));
+                },
+                None => sent_all = true,
+            }
+        }
+
+        if pending.is_empty() {
+            break;
+        }
+
+        // wait for a reply, or until the oldest request times out
+        let res = match timeout {
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            Some(t) => {
+                let oldest = pending.values().map(|&(_, sent)| sent).min().unwrap();
+                let now = Instant::now();
+                let wait = if oldest + t > now { oldest + t - now } else { Duration::from_millis(0) };
+                rx.recv_timeout(wait)
+            }
+        };
+
+        match res {
+            Ok(None) => {
+                cancel_consumer(&mut pub_channel, channel_id, consumer_tag)?;
+                close_channel(&mut pub_channel, channel_id)?;
+                pub_channel.close(200, "Bye")?;
+                session.close(200, "Good Bye");
+                rbterr!("Interrupted with {} requests waiting for a reply", pending.len());
+            },
+            Ok(Some((reply_props, body))) => {
+                let correlation_id = reply_props.correlation_id.clone().unwrap_or("".to_owned());
+                match pending.remove(&correlation_id) {
+                    Some((idx, _)) =>
+                        on_reply(idx, &correlation_id, BatchReply::Reply(reply_props, body))?,
+                    None => errln!("Discarding reply with unknown correlation id: {}", correlation_id),
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                let now = Instant::now();
+                let t = timeout.unwrap();
+                let expired:Vec<String> = pending.iter()
+                    .filter(|&(_, &(_, sent))| sent + t <= now)
+                    .map(|(c, _)| c.clone())
+                    .collect();
+                for correlation_id in expired {
+                    let (idx, _) = pending.remove(&correlation_id).unwrap();
+                    on_reply(idx, &correlation_id, BatchReply::Timeout)?;
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                rbterr!("Connection lost with {} requests waiting for a reply", pending.len());
+            },
+        }
+    }
+
+    pub_channel.close(200, "Bye")?;
+    session.close(200, "Good Bye");
+
+    Ok(())
+}
+
+
+// the properties to send, with headers parsed from input
+pub fn build_props(s:&Sendable) -> Result<BasicProperties,RbtError> {
+
+    // table of headers, parsed from input
+    let mut headers = Table::new();
+    for st in &s.headers {
+        let (key, val) = parse_entry(st, ':').ok_or("Header must have a :")?;
+        headers.insert(key, val);
+    }
+
+    // put filename in headers if we read from file
+    if s.file_name != "-" && !headers.contains_key("fileName") {
+        headers.insert("fileName".to_owned(), TableEntry::LongString(s.file_name.clone()));
+    }
+
+    Ok(BasicProperties {
+        content_type: Some(s.content_type.clone()),
+        headers: Some(headers),
+        priority: Some(s.priority),
+        ..Default::default()
+    })
+}
+
+
+fn gen_correlation_id() -> String {
+    let rand:String = thread_rng().sample_iter(&Alphanumeric).take(24).collect();
+    format!("rabbiteer-{}", rand)
+}
+
+
+// like narrow, but whole numbers stay integers, as queue
+// arguments such as x-max-length must be.
+pub fn narrow_int(str:&str) -> TableEntry {
+    match str.parse::<i64>() {
+        Ok(i) => TableEntry::LongLongInt(i),
+        Err(_) => narrow(str),
+    }
+}
+
+
+// "My-Header: Value" into a key and narrowed value, split on sep
+pub fn parse_entry(st:&str, sep:char) -> Option<(String, TableEntry)> {
+    let idx = st.find(sep)?;
+    let (name, value) = st.split_at(idx);
+    let key = name.trim();
+    let valstr = (&value[1..]).trim();
+    Some((String::from(key), narrow(valstr)))
+}
+
+
 // narrow the string to a TableEntry type by trying to parse to known
 // JSON types: bool, double and fall back on string.
 fn narrow(str:&str) -> TableEntry {
@@ -139,6 +534,53 @@ fn narrow(str:&str) -> TableEntry {
 }
 
 
+// declare the exchange, or passively check that it exists. a failed
+// declare closes the channel, which we turn into a readable error.
+fn declare_exchange(channel:&mut Channel, vhost:&str, exchange:&str,
+                    d:&ExchangeDeclare) -> Result<(),RbtError> {
+
+    let declare = protocol::exchange::Declare {
+        ticket: 0,
+        exchange: exchange.to_owned(),
+        _type: d.exchange_type.clone().unwrap_or("".to_owned()),
+        passive: d.exchange_type.is_none(),
+        durable: d.durable,
+        auto_delete: d.auto_delete,
+        internal: false,
+        nowait: false,
+        arguments: Table::new(),
+    };
+
+    let frame = channel.raw_rpc(&declare)?;
+
+    match frame.method_name() {
+        "exchange.declare-ok" => Ok(()),
+        "channel.close" => {
+            let (code, text) = close_reason(frame.arguments.inner());
+            if code == 404 {
+                rbterr!("exchange {} does not exist in vhost {}", exchange, vhost);
+            }
+            rbterr!("Failed to declare exchange {}: {}", exchange, text);
+        },
+        m => rbterr!("Unexpected reply to exchange.declare: {}", m),
+    }
+}
+
+
+// reply_code and reply_text from the arguments of a channel.close
+fn close_reason(args:&[u8]) -> (u16, String) {
+    if args.len() < 3 {
+        return (0, "".to_owned());
+    }
+    let code = ((args[0] as u16) << 8) | args[1] as u16;
+    let len = args[2] as usize;
+    let text = args.get(3..3 + len)
+        .map(|t| String::from_utf8_lossy(t).into_owned())
+        .unwrap_or("".to_owned());
+    (code, text)
+}
+
+
 fn _open(o:Options) -> Result<(Session, Channel),RbtError> {
 //    errln!("Connecting to amqp://{}:{}@{}:{}/{}",
 //           o.login, o.password, o.host, o.port, o.vhost);
@@ -150,46 +592,413 @@ fn _open(o:Options) -> Result<(Session, Channel),RbtError> {
 impl amqp::Consumer for Receiver {
     fn handle_delivery(&mut self, channel:&mut Channel, deliver:Deliver,
                        headers:BasicProperties, body:Vec<u8>){
+        self.deliver(channel, deliver, headers, body);
+    }
+}
+
+impl Receiver {
+
+    // hand the message to the callback and settle it. false when it
+    // wasn't for the callback at all.
+    fn deliver(&mut self, channel:&mut Channel, deliver:Deliver,
+               headers:BasicProperties, body:Vec<u8>) -> bool {
 
         let delivery_tag = deliver.delivery_tag.clone();
 
-        if self.auto_ack {
-            // ack it. make it go away.
-            channel.basic_ack(delivery_tag, false).unwrap();
+        // a reply that isn't for us
+        if let Some(ref expected) = self.correlation_id {
+            if headers.correlation_id.as_ref() != Some(expected) {
+                errln!("Discarding reply with mismatched correlation id: {}",
+                       headers.correlation_id.as_ref().map(|c| c.as_ref()).unwrap_or("<none>"));
+                if self.auto_ack {
+                    channel.basic_ack(delivery_tag, false).unwrap_or_else(|e| ::error::handle(e.into()));
+                }
+                return false;
+            }
+        }
+
+        // filtered out
+        if let Some(ref filter) = self.filter {
+            if !filter(&deliver, &headers, &body) {
+                if self.auto_ack {
+                    channel.basic_ack(delivery_tag, false)
+                        .unwrap_or_else(|e| errln!("Failed to ack {}: {}", delivery_tag, e));
+                }
+                return false;
+            }
+        }
+
+        // the body is gone once the callback has it
+        let copy = match self.on_error {
+            OnError::DeadLetter(Some(_)) if self.auto_ack =>
+                Some((deliver.exchange.clone(), deliver.routing_key.clone(), headers.clone(), body.clone())),
+            _ => None,
+        };
+
+        // deliver to callback
+        let err = match (self.callback)(channel, deliver, headers, body) {
+            Ok(_) => {
+                // only ack what the callback is done with, so nothing is
+                // lost if it fails or we're killed halfway. the session
+                // may be closing under us once an rpc reply is in.
+                if self.auto_ack {
+                    channel.basic_ack(delivery_tag, false)
+                        .unwrap_or_else(|e| errln!("Failed to ack {}: {}", delivery_tag, e));
+                }
+                return true;
+            },
+            Err(e) => e,
+        };
+
+        self.failures.lock().unwrap().push((delivery_tag, err.to_string()));
+
+        if let OnError::Exit = self.on_error {
+            if self.auto_ack {
+                channel.basic_nack(delivery_tag, false, true)
+                    .unwrap_or_else(|e| errln!("Failed to nack {}: {}", delivery_tag, e));
+            }
+            print_failures(&self.failures);
+            ::error::handle(err);
+            return true;
         }
 
-        // and deliver to callback
-        ((self.callback)(channel, deliver, headers, body)).unwrap_or_else(::error::handle);
+        errln!("Message {} failed: {}", delivery_tag, err);
+
+        // with noack, it stays unacked
+        if !self.auto_ack {
+            return true;
+        }
 
+        let settled = match self.on_error {
+            OnError::Exit       => unreachable!(),
+            OnError::Skip       => channel.basic_ack(delivery_tag, false).map_err(RbtError::from),
+            OnError::Nack       => channel.basic_nack(delivery_tag, false, false).map_err(RbtError::from),
+            OnError::Requeue    => channel.basic_nack(delivery_tag, false, true).map_err(RbtError::from),
+            OnError::DeadLetter(None) => channel.basic_nack(delivery_tag, false, false).map_err(RbtError::from),
+            OnError::DeadLetter(Some(ref exchange)) => {
+                let (from, routing_key, props, body) = copy.unwrap();
+                dead_letter(channel, exchange, from, routing_key, props, body, &err)
+                    .and_then(|_| channel.basic_ack(delivery_tag, false).map_err(RbtError::from))
+            },
+        };
+
+        settled.unwrap_or_else(|e| errln!("Failed to settle {}: {}", delivery_tag, e));
+        true
     }
 }
 
-pub fn open_receive(o:Options, q:Option<String>, force_declare: bool, r:Receiver) -> Result<(),RbtError> {
+
+// republish a failed message to the dead letter exchange, with the
+// same routing key and the error in the x-rabbiteer-error header.
+fn dead_letter(channel:&mut Channel, exchange:&str, from:String, routing_key:String,
+               mut props:BasicProperties, body:Vec<u8>, err:&RbtError) -> Result<(),RbtError> {
+
+    let mut headers = props.headers.take().unwrap_or(Table::new());
+    headers.insert("x-rabbiteer-error".to_owned(), TableEntry::LongString(err.to_string()));
+    headers.insert("x-original-exchange".to_owned(), TableEntry::LongString(from));
+    props.headers = Some(headers);
+
+    channel.basic_publish(exchange.to_owned(), routing_key, false, false, props, body)?;
+
+    Ok(())
+}
+
+
+// the summary of failed messages, if there were any
+pub fn print_failures(failures:&Failures) {
+    let failures = failures.lock().unwrap();
+    if failures.is_empty() {
+        return;
+    }
+    errln!("{} message(s) failed:", failures.len());
+    for &(tag, ref e) in failures.iter() {
+        errln!("  {}: {}", tag, e);
+    }
+}
+
+// when a subscription stops by itself
+#[derive(Default)]
+pub struct Limits {
+    // after this many messages
+    pub count: Option<u64>,
+    // when no message arrived for this long
+    pub idle_timeout: Option<Duration>,
+    // once the messages that were in the queue at start are consumed
+    pub drain: bool,
+}
+
+// what the consumer thread (and signals) tell the main thread
+enum Event {
+    Message,
+    Done,
+    Closed,
+    Signal,
+}
+
+// wraps a receiver to count messages, and to hold back messages
+// once we are stopping.
+struct Limited {
+    receiver: Receiver,
+    left: Option<u64>,
+    stop: Arc<Mutex<bool>>,
+    events: mpsc::Sender<Event>,
+}
+
+impl amqp::Consumer for Limited {
+    fn handle_delivery(&mut self, channel:&mut Channel, deliver:Deliver,
+                       headers:BasicProperties, body:Vec<u8>){
+
+        // held while we handle the message, so stopping waits for us
+        let stop = self.stop.clone();
+        let stopped = stop.lock().unwrap();
+
+        // past the limit, it goes back to the queue
+        if *stopped || self.left == Some(0) {
+            if self.receiver.auto_ack {
+                channel.basic_nack(deliver.delivery_tag, false, true)
+                    .unwrap_or_else(|e| errln!("Failed to nack {}: {}", deliver.delivery_tag, e));
+            }
+            return;
+        }
+
+        // filtered out messages are still traffic, but don't count
+        let delivered = self.receiver.deliver(channel, deliver, headers, body);
+        let _ = self.events.send(Event::Message);
+        if !delivered {
+            return;
+        }
+
+        if let Some(ref mut left) = self.left {
+            *left -= 1;
+            if *left == 0 {
+                let _ = self.events.send(Event::Done);
+            }
+        }
+    }
+}
+
+
+pub fn open_receive(o:Options, q:Option<String>, declare:Option<QueueDeclare>,
+                    limits:Limits, r:Receiver) -> Result<(),RbtError> {
 
     // open session/channel
-    let (_, mut channel) = _open(o)?;
+    let (mut session, mut channel) = _open(o)?;
 
-    // and pass it to internal open_receive
-    do_open_receive(&mut channel, q, force_declare, r)?;
+    let queue_name = setup_queue(&mut channel, q, declare, &r)?;
+
+    let mut left = limits.count;
+    if limits.drain {
+        // queue, passive, durable, exclusive, auto_delete, nowait, arguments
+        let declared = channel.queue_declare(queue_name.clone(), true, false, false, false, false, Table::new())?;
+        let n = declared.message_count as u64;
+        left = Some(left.map(|c| cmp::min(c, n)).unwrap_or(n));
+    }
 
-    // and go!
-    channel.start_consuming();
+    // nothing to wait for
+    if left == Some(0) {
+        channel.close(200, "Bye")?;
+        session.close(200, "Good Bye");
+        return Ok(());
+    }
+
+    let stop = Arc::new(Mutex::new(false));
+    let (tx, rx) = mpsc::channel();
+    let arguments = r.arguments.clone();
+    let limited = Limited {
+        receiver: r,
+        left: left,
+        stop: stop.clone(),
+        events: tx.clone(),
+    };
+
+    // callback, queue, consumer_tag, no_local, no_ack, exclusive, nowait, arguments
+    let consumer_tag = channel.basic_consume(limited, queue_name, "".to_string(), false,
+                                             false, false, false, arguments)?;
+    let channel_id = channel.id;
+
+    // the consumer thread is stuck reading its channel, so we
+    // talk to the broker about it on a channel of our own.
+    let mut control = session.open_channel(channel_id + 1)?;
+
+    // stop nicely on ctrl-c or kill
+    {
+        let tx = tx.clone();
+        on_signal(move || { let _ = tx.send(Event::Signal); })?;
+    }
+
+    // and go! the consumer has the channel from here on.
+    thread::Builder::new().name("consumer_thread".to_string()).spawn(move || {
+        channel.start_consuming();
+        let _ = tx.send(Event::Closed);
+    })?;
+
+    loop {
+        let event = match limits.idle_timeout {
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            Some(t) => rx.recv_timeout(t),
+        };
+        match event {
+            Ok(Event::Message) => (),
+            Ok(Event::Done) | Ok(Event::Signal) | Err(mpsc::RecvTimeoutError::Timeout) => break,
+            Ok(Event::Closed) | Err(mpsc::RecvTimeoutError::Disconnected) => {
+                rbterr!("Connection lost while consuming");
+            },
+        }
+    }
+
+    // no more deliveries
+    cancel_consumer(&mut control, channel_id, consumer_tag)?;
+
+    // let the message in hand finish and settle its ack. anything
+    // after it is nacked back to the queue.
+    *stop.lock().unwrap() = true;
+
+    close_channel(&mut control, channel_id)?;
+
+    control.close(200, "Bye")?;
+    session.close(200, "Good Bye");
 
     Ok(())
 }
 
 
-fn do_open_receive(channel:&mut Channel, q:Option<String>, force_declare: bool, r:Receiver) -> Result<String,RbtError> {
+// call notify on ctrl-c or kill, so we can stop nicely. a second
+// one means now.
+fn on_signal<F>(mut notify:F) -> Result<(),RbtError>
+    where F: FnMut() + Send + 'static {
+    let mut signalled = false;
+    ctrlc::set_handler(move || {
+        if signalled {
+            ::std::process::exit(130);
+        }
+        signalled = true;
+        notify();
+    }).map_err(|e| format!("Failed to set signal handler: {}", e))?;
+    Ok(())
+}
+
+// a consumer thread is stuck reading its channel, so we talk to the
+// broker about it on a control channel of our own. nowait, since the
+// cancel-ok would go to the consumer thread, not us.
+fn cancel_consumer(control:&mut Channel, channel_id:u16, consumer_tag:String) -> Result<(),RbtError> {
+    let cancel = protocol::basic::Cancel { consumer_tag: consumer_tag, nowait: true };
+    control.write(cancel.to_frame(channel_id).map_err(AMQPError::from)?)?;
+    Ok(())
+}
+
+// close the consumer channel, its close-ok also ends up in the
+// consumer thread, which ignores it.
+fn close_channel(control:&mut Channel, channel_id:u16) -> Result<(),RbtError> {
+    let close = protocol::channel::Close {
+        reply_code: 200,
+        reply_text: "Bye".to_owned(),
+        class_id: 0,
+        method_id: 0,
+    };
+    control.write(close.to_frame(channel_id).map_err(AMQPError::from)?)?;
+    Ok(())
+}
+
+
+// take up to count messages from the queue with basic.get and hand
+// them to on_msg with the number of messages left. they are all acked,
+// or all put back, at the end. returns how many there were.
+pub fn open_get<F>(o:Options, queue:&str, count:u64, ack:bool,
+                   mut on_msg:F) -> Result<u64,RbtError>
+    where F: FnMut(Deliver, BasicProperties, Vec<u8>, u32) -> Result<(),RbtError> {
+
+    let (mut session, mut channel) = _open(o)?;
+
+    let mut got = 0;
+    let mut last_tag = None;
+    let mut res = Ok(());
+
+    // queue, no_ack
+    for m in channel.basic_get(queue, false) {
+        let deliver = Deliver {
+            consumer_tag: "".to_owned(),
+            delivery_tag: m.reply.delivery_tag,
+            redelivered: m.reply.redelivered,
+            exchange: m.reply.exchange.clone(),
+            routing_key: m.reply.routing_key.clone(),
+        };
+        last_tag = Some(m.reply.delivery_tag);
+        got += 1;
+
+        res = on_msg(deliver, m.headers, m.body, m.reply.message_count);
+        if res.is_err() || got == count {
+            break;
+        }
+    }
+
+    // settle them all in one go. putting them back one at a time
+    // would make the next get return the same message.
+    if let Some(tag) = last_tag {
+        if ack && res.is_ok() {
+            channel.basic_ack(tag, true)?;
+        } else {
+            channel.basic_nack(tag, true, true)?;
+        }
+    }
+
+    channel.close(200, "Bye")?;
+    session.close(200, "Good Bye");
+
+    res.map(|_| got)
+}
+
+
+// the pseudo-queue for RabbitMQ direct reply-to
+static DIRECT_REPLY_TO:&'static str = "amq.rabbitmq.reply-to";
+
+// consume rpc replies via direct reply-to. this needs no queue
+// declaration, but must be in no-ack mode.
+// the queue name and consumer tag
+fn do_open_direct_reply(channel:&mut Channel, mut r:Receiver) -> Result<(String, String),RbtError> {
+
+    // acking in no-ack mode is a channel error
+    r.auto_ack = false;
+
+    // callback, queue, consumer_tag, no_local, no_ack, exclusive, nowait, arguments
+    let consumer_tag = channel.basic_consume(r, DIRECT_REPLY_TO.to_owned(), "".to_owned(), false,
+                                             true, false, false, Table::new())?;
+
+    Ok((DIRECT_REPLY_TO.to_owned(), consumer_tag))
+}
+
+
+// the queue name and consumer tag
+fn do_open_receive(channel:&mut Channel, q:Option<String>, declare:Option<QueueDeclare>,
+                   r:Receiver) -> Result<(String, String),RbtError> {
+
+    let queue_name = setup_queue(channel, q, declare, &r)?;
 
-    let mut auto_delete = false;
-    let mut bind_routing_key = r.routing_key.clone();
+    // why oh why?
+    let consumer_tag = "".to_string();
+
+    // start consuming the queue.
+    // callback, queue, consumer_tag, no_local, no_ack, exclusive, nowait, arguments
+    let arguments = r.arguments.clone();
+    let consumer_tag = channel.basic_consume(r, queue_name.clone(), consumer_tag, false,
+                                             false, false, false, arguments)?;
+
+    Ok((queue_name, consumer_tag))
+}
+
+
+// declare and bind the queue as needed, and get its name
+fn setup_queue(channel:&mut Channel, q:Option<String>, declare:Option<QueueDeclare>,
+               r:&Receiver) -> Result<String,RbtError> {
+
+    let mut default_routing_key = None;
     
     let queue_name = match q {
         Some(q) => {
             // Force the declaration of this queue
-            if force_declare {
+            if let Some(d) = declare {
                 // queue, passive, durable, exclusive, auto_delete, nowait, arguments
-                let queue_declare =  channel.queue_declare(q, false, false, auto_delete, auto_delete, false, Table::new())?;
+                let queue_declare = channel.queue_declare(q, false, d.durable, d.exclusive, d.auto_delete,
+                                                          false, d.arguments)?;
 
                 // name is auto generated
                 queue_declare.queue
@@ -198,18 +1007,17 @@ fn do_open_receive(channel:&mut Channel, q:Option<String>, force_declare: bool,
             }
         }
         None => {
-            auto_delete = true; // Unnamed queues are ephemeral
+            let d = declare.unwrap_or_default();
 
-            if let None = bind_routing_key {
-                bind_routing_key = Some("#".to_owned()); // Default the routing key
-            }
+            default_routing_key = Some("#".to_owned()); // Default the routing key
 
+            // Unnamed queues are ephemeral
             // queue, passive, durable, exclusive, auto_delete, nowait, arguments
             let queue_declare =
                 channel.queue_declare(
-                                    q.clone().unwrap_or("".to_owned()) ,
-                                    false, false,
-                                    auto_delete, auto_delete, false, Table::new())?;
+                                    "".to_owned(),
+                                    false, d.durable,
+                                    true, true, false, d.arguments)?;
 
             // name is auto generated
             queue_declare.queue
@@ -217,23 +1025,23 @@ fn do_open_receive(channel:&mut Channel, q:Option<String>, force_declare: bool,
         }
     };
 
-    // Only bind if we have a routing key - May be an existing queue
-    if let Some(routing_key) = bind_routing_key {
-        // bind queue to the exchange, which already must be declared.
-        
-        if r.exchange != "" {
-            channel.queue_bind(queue_name.clone(), r.exchange.clone(), routing_key.clone(),
-                            false, Table::new())?;
+    for b in &r.bindings {
+        // Only bind if we have a routing key - May be an existing queue
+        if let Some(routing_key) = b.bind_key(default_routing_key.as_ref()) {
+            // bind queue to the exchange, which already must be declared.
+
+            if b.exchange != "" {
+                channel.queue_bind(queue_name.clone(), b.exchange.clone(), routing_key,
+                                false, b.arguments.clone())?;
+            }
         }
     }
 
-    // why oh why?
-    let consumer_tag = "".to_string();
-
-    // start consuming the queue.
-    // callback, queue, consumer_tag, no_local, no_ack, exclusive, nowait, arguments
-    channel.basic_consume(r, queue_name.clone(), consumer_tag, false,
-                          false, false, false, Table::new())?;
+    // limit how many unacked messages the broker pushes at us
+    if let Some((count, global)) = r.prefetch {
+        // prefetch_size, prefetch_count, global
+        channel.basic_qos(0, count, global)?;
+    }
 
     Ok(queue_name)
 }
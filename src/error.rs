@@ -29,8 +29,12 @@ pub enum RbtError {
     UTF8(FromUtf8Error),
     JSON(json::ParserError),
     Clap(clap::Error),
+    Timeout(u64),     // No rpc reply within millis
 }
 
+// exit code when an rpc reply times out, like timeout(1)
+pub const EXIT_TIMEOUT:i32 = 124;
+
 
 impl fmt::Display for RbtError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -41,6 +45,7 @@ impl fmt::Display for RbtError {
             RbtError::UTF8(ref e)    => write!(f, "{}", e),
             RbtError::JSON(ref e)    => write!(f, "{}", e),
             RbtError::Clap(ref e)    => write!(f, "{}", e),
+            RbtError::Timeout(ms)    => write!(f, "Error: No rpc reply within {} ms", ms),
         }
     }
 }
@@ -48,7 +53,11 @@ impl fmt::Display for RbtError {
 
 pub fn handle(e:RbtError) {
     errln!("{}", e);
-    ::std::process::exit(1);
+    let code = match e {
+        RbtError::Timeout(_) => EXIT_TIMEOUT,
+        _ => 1,
+    };
+    ::std::process::exit(code);
 }
 
 
@@ -0,0 +1,180 @@
+use rustc_serialize::json::Json;
+use regex::Regex;
+use glob;
+use jsonpath;
+
+
+// how the value is tested
+enum Op {
+    Exists,
+    Eq(Json),
+    Ne(Json),
+    Glob(String),
+    Regex(Regex),
+}
+
+// one --filter, tested against output::message_json
+pub struct Filter {
+    path: String,
+    op: Op,
+}
+
+
+impl Filter {
+
+    // parse an expression such as
+    //
+    //   .order.status == "failed"
+    //   routing_key ~ "orders.*"
+    //   routing_key =~ /^orders\.(eu|us)\./
+    //   headers.region != eu
+    //   props.content_type == "application/json"
+    //   .order.id
+    pub fn parse(expr:&str) -> Result<Filter,String> {
+
+        let expr = expr.trim();
+        let end = expr.find(|c:char| c.is_whitespace() || c == '=' || c == '!' || c == '~')
+            .unwrap_or(expr.len());
+        let (lhs, rest) = expr.split_at(end);
+        let path = to_path(lhs)?;
+        jsonpath::check(&path).map_err(|_| format!("Bad filter: {}", expr))?;
+
+        let rest = rest.trim();
+        let op = if rest == "" {
+            Op::Exists
+        } else if rest.starts_with("==") {
+            Op::Eq(literal(&rest[2..]))
+        } else if rest.starts_with("!=") {
+            Op::Ne(literal(&rest[2..]))
+        } else if rest.starts_with("=~") {
+            let pat = rest[2..].trim();
+            let pat = if pat.len() > 1 && pat.starts_with('/') && pat.ends_with('/') {
+                pat[1..pat.len() - 1].to_owned()
+            } else {
+                string_of(&literal(pat))
+            };
+            Op::Regex(Regex::new(&pat).map_err(|e| format!("Bad regex in filter {}: {}", expr, e))?)
+        } else if rest.starts_with('~') {
+            Op::Glob(string_of(&literal(&rest[1..])))
+        } else {
+            return Err(format!("Bad filter: {}, expected ==, !=, ~ or =~", expr));
+        };
+
+        Ok(Filter { path: path, op: op })
+    }
+
+
+    pub fn matches(&self, msg:&Json) -> bool {
+        let value = jsonpath::lookup(msg, &self.path);
+        match self.op {
+            Op::Exists       => value.map(|v| !v.is_null()).unwrap_or(false),
+            Op::Eq(ref e)    => value.map(|v| jsonpath::value_matches(e, v)).unwrap_or(false),
+            Op::Ne(ref e)    => !value.map(|v| jsonpath::value_matches(e, v)).unwrap_or(false),
+            Op::Glob(ref p)  => value.map(|v| glob::glob_match(p, &string_of(v))).unwrap_or(false),
+            Op::Regex(ref r) => value.map(|v| r.is_match(&string_of(v))).unwrap_or(false),
+        }
+    }
+}
+
+
+// the left hand side as a path into the message json
+fn to_path(lhs:&str) -> Result<String,String> {
+    let quoted = |s:&str| Json::String(s.to_owned()).to_string();
+    if lhs.starts_with('.') {
+        // the body
+        Ok(format!(".body{}", if lhs == "." { "" } else { lhs }))
+    } else if lhs.starts_with("headers.") {
+        Ok(format!(".props.headers[{}]", quoted(&lhs[8..])))
+    } else if lhs.starts_with("props.") {
+        Ok(format!(".props[{}]", quoted(&lhs[6..])))
+    } else {
+        match lhs {
+            "exchange" | "routing_key" | "redelivered" | "delivery_tag" | "consumer_tag" =>
+                Ok(format!(".deliver.{}", lhs)),
+            _ => Err(format!("Bad filter field: {}, use .path, headers.name, props.name, \
+                              exchange or routing_key", lhs)),
+        }
+    }
+}
+
+// a JSON literal, or else a bare string
+fn literal(s:&str) -> Json {
+    let s = s.trim();
+    Json::from_str(s).unwrap_or_else(|_| Json::String(s.to_owned()))
+}
+
+fn string_of(v:&Json) -> String {
+    match *v {
+        Json::String(ref s) => s.clone(),
+        ref other => other.to_string(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg() -> Json {
+        Json::from_str(r#"{
+            "deliver": {"exchange": "orders", "routing_key": "orders.eu.created", "redelivered": false},
+            "props": {"content_type": "application/json", "headers": {"region": "eu", "retry": 2}},
+            "body": {"order": {"id": 7, "status": "failed"}}
+        }"#).unwrap()
+    }
+
+    fn matches(expr:&str) -> bool {
+        Filter::parse(expr).unwrap_or_else(|e| panic!("{}", e)).matches(&msg())
+    }
+
+    #[test]
+    fn equals() {
+        assert!(matches(".order.status == \"failed\""));
+        assert!(matches(".order.status==failed"));
+        assert!(matches(".order.id == 7"));
+        assert!(matches("headers.retry == \"2\""));
+        assert!(matches("props.content_type == \"application/json\""));
+        assert!(matches("redelivered == false"));
+        assert!(!matches(".order.status == \"ok\""));
+        assert!(!matches(".order.missing == 1"));
+    }
+
+    #[test]
+    fn not_equals() {
+        assert!(matches("headers.region != us"));
+        assert!(!matches("headers.region != eu"));
+        assert!(matches("headers.missing != eu"));
+    }
+
+    #[test]
+    fn globs() {
+        assert!(matches("routing_key ~ \"orders.*\""));
+        assert!(matches("exchange ~ ord?rs"));
+        assert!(!matches("routing_key ~ \"payments.*\""));
+    }
+
+    #[test]
+    fn regexes() {
+        assert!(matches("routing_key =~ /^orders\\.(eu|us)\\./"));
+        assert!(matches("routing_key =~ \"created$\""));
+        assert!(!matches("routing_key =~ /^orders\\.us\\./"));
+        assert!(matches(".order.id =~ /^7$/"));
+    }
+
+    #[test]
+    fn exists() {
+        assert!(matches(".order.id"));
+        assert!(matches("."));
+        assert!(!matches(".order.missing"));
+        assert!(!matches("headers.missing"));
+    }
+
+    #[test]
+    fn bad_filters() {
+        let err = |expr:&str| Filter::parse(expr).err().expect(expr);
+        assert!(err("queue == x").starts_with("Bad filter field: queue"));
+        assert!(err("routing_key < x").starts_with("Bad filter: routing_key < x, expected"));
+        assert!(err("routing_key =~ /(/").starts_with("Bad regex in filter"));
+        assert!(err(".a[x] == 1").starts_with("Bad filter: .a[x]"));
+    }
+}
@@ -0,0 +1,40 @@
+use std::io::{self, Write};
+use amqp;
+use clap::ArgMatches;
+use client;
+use output;
+use error::RbtError;
+
+
+pub fn do_get(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtError> {
+
+    let queue = value_t!(matches, "queue", String)?;
+    let count = value_t!(matches, "count", u64)?;
+    let ack   = matches.is_present("ack");
+
+    if count == 0 {
+        rbterr!("--count must be at least 1");
+    }
+
+    let got = client::open_get(opts, &queue, count, ack, |deliver, props, body, message_count| {
+
+        let msg = output::build_get_output(&deliver, &props, body, message_count)?;
+
+        let stdout = io::stdout();
+
+        // lock until end of scope
+        let mut handle = stdout.lock();
+
+        handle.write_all(&msg)?;
+        handle.write_all(b"\n")?;
+        handle.flush()?;
+
+        Ok(())
+    })?;
+
+    if got == 0 {
+        errln!("Queue {} is empty", queue);
+    }
+
+    Ok(())
+}
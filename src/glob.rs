@@ -0,0 +1,65 @@
+// match s against a glob pattern where * is any run of
+// characters and ? is any one character.
+pub fn glob_match(pattern:&str, s:&str) -> bool {
+
+    let p:Vec<char> = pattern.chars().collect();
+    let s:Vec<char> = s.chars().collect();
+
+    let (mut pi, mut si) = (0, 0);
+
+    // where to go back to when a * needs to eat one more character
+    let mut star:Option<(usize, usize)> = None;
+
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, si));
+            pi += 1;
+        } else if let Some((sp, ss)) = star {
+            pi = sp + 1;
+            si = ss + 1;
+            star = Some((sp, ss + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal() {
+        assert!(glob_match("orders", "orders"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("orders", "order"));
+        assert!(!glob_match("order", "orders"));
+    }
+
+    #[test]
+    fn stars() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("orders.*", "orders.eu.created"));
+        assert!(glob_match("*.created", "orders.eu.created"));
+        assert!(glob_match("orders.*.created", "orders.eu.created"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(glob_match("**", "x"));
+        assert!(!glob_match("orders.*", "payments.eu"));
+        assert!(!glob_match("a*b", "aXbY"));
+    }
+
+    #[test]
+    fn question_marks() {
+        assert!(glob_match("ord?rs", "orders"));
+        assert!(glob_match("?*", "x"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("ord?rs", "ordrs"));
+    }
+}
@@ -0,0 +1,193 @@
+// parse a date or date-time such as 2024-05-01, 2024-05-01T12:00:00Z
+// or 2024-05-01T14:00:00+02:00 into seconds since the epoch. without
+// a zone, the time is UTC.
+pub fn parse(s:&str) -> Option<u64> {
+
+    let s = s.trim();
+    let (date, time) = match s.find(|c| c == 'T' || c == ' ') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    };
+
+    let d:Vec<&str> = date.split('-').collect();
+    if d.len() != 3 || d[0].len() != 4 || d[1].len() != 2 || d[2].len() != 2 {
+        return None;
+    }
+    let year:i64 = d[0].parse().ok()?;
+    let month:u32 = d[1].parse().ok()?;
+    let day:u32 = d[2].parse().ok()?;
+    if month < 1 || month > 12 || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+
+    // split off the zone, Z or +HH:MM / -HH:MM
+    let (clock, zone_secs) = if time.ends_with('Z') {
+        (&time[..time.len() - 1], 0)
+    } else if let Some(i) = time.rfind(|c| c == '+' || c == '-') {
+        // +hh, +hhmm or +hh:mm
+        let zone = time[i + 1..].replace(':', "");
+        if (zone.len() != 2 && zone.len() != 4) || !zone.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let h:i64 = zone[..2].parse().ok()?;
+        let m:i64 = if zone.len() == 4 { zone[2..].parse().ok()? } else { 0 };
+        if h > 23 || m > 59 {
+            return None;
+        }
+        let secs = h * 3600 + m * 60;
+        (&time[..i], if &time[i..i + 1] == "-" { -secs } else { secs })
+    } else {
+        (time, 0)
+    };
+
+    let mut secs_of_day = 0;
+    if clock != "" {
+        // fractions of a second are dropped
+        let clock = clock.split('.').next().unwrap_or("");
+        let t:Vec<&str> = clock.split(':').collect();
+        if t.len() < 2 || t.len() > 3 {
+            return None;
+        }
+        let h:i64 = t[0].parse().ok()?;
+        let m:i64 = t[1].parse().ok()?;
+        let sec:i64 = match t.get(2) {
+            Some(v) => v.parse().ok()?,
+            None => 0,
+        };
+        if h > 23 || m > 59 || sec > 60 {
+            return None;
+        }
+        secs_of_day = h * 3600 + m * 60 + sec;
+    }
+
+    let secs = days_from_civil(year, month, day) * 86400 + secs_of_day - zone_secs;
+    if secs < 0 {
+        return None;
+    }
+    Some(secs as u64)
+}
+
+
+// seconds since the epoch as 2024-05-01T12:00:00Z
+pub fn format(secs:u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+
+// days since 1970-01-01 of a (proleptic gregorian) date
+fn days_from_civil(year:i64, month:u32, day:u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let m = month as i64;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+
+// 28 to 31, with leap years
+fn days_in_month(year:i64, month:u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+
+// the other way around, the date of days since 1970-01-01
+fn civil_from_days(days:i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_and_times() {
+        assert_eq!(parse("1970-01-01"), Some(0));
+        assert_eq!(parse("2024-05-01T12:00:00Z"), Some(1714564800));
+        assert_eq!(parse("2024-05-01 12:00:00"), Some(1714564800));
+        assert_eq!(parse("2024-05-01T12:00"), Some(1714564800));
+        assert_eq!(parse("2024-05-01T12:00:00.250Z"), Some(1714564800));
+        assert_eq!(parse("2000-02-29T23:59:59Z"), Some(951868799));
+    }
+
+    #[test]
+    fn zones() {
+        assert_eq!(parse("2024-05-01T14:00:00+02:00"), Some(1714564800));
+        assert_eq!(parse("2024-05-01T14:00:00+0200"), Some(1714564800));
+        assert_eq!(parse("2024-05-01T14:00:00+02"), Some(1714564800));
+        assert_eq!(parse("2024-05-01T06:30:00-05:30"), Some(1714564800));
+    }
+
+    #[test]
+    fn bad() {
+        assert_eq!(parse("bad"), None);
+        assert_eq!(parse("2024-13-01"), None);
+        assert_eq!(parse("2024-05-32"), None);
+        assert_eq!(parse("24-05-01"), None);
+        assert_eq!(parse("2024-05-01T24:00:00Z"), None);
+        assert_eq!(parse("2024-05-01T12:60:00Z"), None);
+        assert_eq!(parse("1969-12-31T23:59:59Z"), None);
+    }
+
+    #[test]
+    fn days_of_month() {
+        assert_eq!(parse("2024-02-29"), Some(1709164800));
+        assert_eq!(parse("2000-02-29"), Some(951782400));
+        assert_eq!(parse("2024-02-30"), None);
+        assert_eq!(parse("2024-02-31"), None);
+        assert_eq!(parse("2023-02-29"), None);
+        assert_eq!(parse("1900-02-29"), None);
+        assert_eq!(parse("2024-04-31"), None);
+        assert_eq!(parse("2024-04-30"), Some(1714435200));
+        assert_eq!(parse("2024-12-31"), Some(1735603200));
+    }
+
+    #[test]
+    fn bad_zones() {
+        assert_eq!(parse("2024-05-01T12:00:00+24:00"), None);
+        assert_eq!(parse("2024-05-01T12:00:00+02:60"), None);
+        assert_eq!(parse("2024-05-01T12:00:00+9999"), None);
+        assert_eq!(parse("2024-05-01T12:00:00+2"), None);
+        assert_eq!(parse("2024-05-01T12:00:00+02:0x"), None);
+    }
+
+    #[test]
+    fn formatting() {
+        assert_eq!(format(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format(1714564800), "2024-05-01T12:00:00Z");
+        assert_eq!(format(951868799), "2000-02-29T23:59:59Z");
+        assert_eq!(format(4107542401), "2100-03-01T00:00:01Z");
+    }
+
+    #[test]
+    fn civil_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(-719468), (0, 3, 1));
+        for days in -800000..800000 {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use rustc_serialize::json::Json;
+
+
+// one step in a path
+enum Seg {
+    Key(String),
+    Index(usize),
+}
+
+
+// parse a jq style path such as .order.items[0].id or .["a key"].
+// a lone . is the document itself.
+fn parse(path:&str) -> Result<Vec<Seg>,String> {
+
+    let bad = || format!("Bad path: {}", path);
+
+    let mut segs = vec![];
+    let chars:Vec<char> = path.trim().chars().collect();
+    let mut i = 0;
+
+    if chars.first() != Some(&'.') {
+        return Err(bad());
+    }
+
+    while i < chars.len() {
+        match chars[i] {
+            '.' => {
+                i += 1;
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '-') {
+                    i += 1;
+                }
+                if i > start {
+                    segs.push(Seg::Key(chars[start..i].iter().collect()));
+                } else if i < chars.len() && chars[i] != '[' {
+                    return Err(bad());
+                }
+            },
+            '[' => {
+                let end = chars[i..].iter().position(|&c| c == ']').ok_or_else(bad)? + i;
+                let inner:String = chars[i + 1..end].iter().collect();
+                let inner = inner.trim();
+                if inner.starts_with('"') {
+                    match Json::from_str(inner) {
+                        Ok(Json::String(k)) => segs.push(Seg::Key(k)),
+                        _ => return Err(bad()),
+                    }
+                } else {
+                    segs.push(Seg::Index(inner.parse().map_err(|_| bad())?));
+                }
+                i = end + 1;
+            },
+            _ => return Err(bad()),
+        }
+    }
+
+    Ok(segs)
+}
+
+
+// check the path syntax up front, so we can complain early.
+pub fn check(path:&str) -> Result<(),String> {
+    parse(path).map(|_| ())
+}
+
+
+// look up the path in the doc. None when it isn't there,
+// or the path is malformed.
+pub fn lookup<'a>(doc:&'a Json, path:&str) -> Option<&'a Json> {
+    let segs = parse(path).ok()?;
+    let mut cur = doc;
+    for seg in segs {
+        cur = match (seg, cur) {
+            (Seg::Key(ref k), &Json::Object(ref o)) => o.get(k)?,
+            (Seg::Index(n), &Json::Array(ref a)) => a.get(n)?,
+            _ => return None,
+        };
+    }
+    Some(cur)
+}
+
+
+// a string matches the string form of any value, so "true"
+// matches a boolean header. numbers compare as numbers.
+pub fn value_matches(expected:&Json, actual:&Json) -> bool {
+    match (expected, actual) {
+        (&Json::String(ref e), &Json::String(ref a)) => e == a,
+        (&Json::String(ref e), a) => *e == a.to_string(),
+        (e, a) if e.is_number() && a.is_number() => e.as_f64() == a.as_f64(),
+        (e, a) => e == a,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc() -> Json {
+        Json::from_str(r#"{"order": {"id": 7, "items": [{"sku": "a"}, {"sku": "b"}]},
+                           "a key": true, "snake_case-key": null}"#).unwrap()
+    }
+
+    #[test]
+    fn good_paths() {
+        for p in &[".", ".order", ".order.items[0].sku", ".[\"a key\"]", ".a[ 1 ]", ".snake_case-key"] {
+            assert!(check(p).is_ok(), "{}", p);
+        }
+    }
+
+    #[test]
+    fn bad_paths() {
+        for p in &["", "order", ".order..id", ".a[x]", ".a[1", ".[\"a]", ".a b", ".a[-1]"] {
+            assert_eq!(check(p), Err(format!("Bad path: {}", p)));
+        }
+    }
+
+    #[test]
+    fn lookups() {
+        let d = doc();
+        assert_eq!(lookup(&d, "."), Some(&d));
+        assert_eq!(lookup(&d, ".order.id"), Some(&Json::U64(7)));
+        assert_eq!(lookup(&d, ".order.items[1].sku"), Some(&Json::String("b".to_owned())));
+        assert_eq!(lookup(&d, ".[\"a key\"]"), Some(&Json::Boolean(true)));
+        assert_eq!(lookup(&d, ".snake_case-key"), Some(&Json::Null));
+    }
+
+    #[test]
+    fn missing() {
+        let d = doc();
+        assert_eq!(lookup(&d, ".nope"), None);
+        assert_eq!(lookup(&d, ".order.items[2]"), None);
+        assert_eq!(lookup(&d, ".order.id.x"), None);
+        assert_eq!(lookup(&d, ".order[0]"), None);
+        assert_eq!(lookup(&d, "order"), None);
+    }
+
+    #[test]
+    fn values() {
+        let j = |s:&str| Json::from_str(s).unwrap();
+        assert!(value_matches(&j("\"eu\""), &j("\"eu\"")));
+        assert!(value_matches(&j("\"true\""), &j("true")));
+        assert!(value_matches(&j("\"2\""), &j("2")));
+        assert!(value_matches(&j("2"), &j("2.0")));
+        assert!(value_matches(&j("null"), &j("null")));
+        assert!(!value_matches(&j("2"), &j("\"2\"")));
+        assert!(!value_matches(&j("\"eu\""), &j("\"us\"")));
+    }
+}
@@ -1,15 +1,29 @@
 extern crate rustc_serialize;
 extern crate amqp;
+extern crate amq_proto;
+extern crate ctrlc;
 extern crate rand;
+extern crate regex;
 extern crate conduit_mime_types as mime;
 extern crate url;
 #[macro_use] extern crate clap;
 
 #[macro_use] mod error;
 mod client;
+mod filter;
+mod get;
+mod glob;
+mod iso8601;
+mod jsonpath;
+mod mock;
 mod output;
 mod publish;
+mod schema;
+mod serve;
+mod sniff;
 mod subscribe;
+mod template;
+mod validate;
 
 use std::env;
 use std::fs;
@@ -85,6 +99,22 @@ fn _main() -> Result<(),RbtError> {
                          .long("routing-key")
                          .takes_value(true)
                          .default_value(""))
+                    .arg(Arg::with_name("declare_exchange")
+                         .help("Declare the exchange with this type (direct, fanout, topic, headers) before publishing.")
+                         .long("declare-exchange")
+                         .takes_value(true))
+                    .arg(Arg::with_name("durable")
+                         .help("Declare the exchange as durable.")
+                         .long("durable")
+                         .requires("declare_exchange"))
+                    .arg(Arg::with_name("auto_delete")
+                         .help("Declare the exchange as auto-delete.")
+                         .long("auto-delete")
+                         .requires("declare_exchange"))
+                    .arg(Arg::with_name("check_exchange")
+                         .help("Check that the exchange exists before publishing.")
+                         .long("check-exchange")
+                         .conflicts_with("declare_exchange"))
                     .arg(Arg::with_name("header")
                          .help("Header on the form \"My-Header: Value\"")
                          .short("H")
@@ -101,11 +131,42 @@ fn _main() -> Result<(),RbtError> {
                     .arg(Arg::with_name("rpc")
                          .help("Publish as RPC with replyTo and wait for reply.")
                          .long("rpc"))
+                    .arg(Arg::with_name("direct_reply")
+                         .help("Receive the RPC reply via RabbitMQ direct reply-to, without declaring a queue.")
+                         .long("direct-reply")
+                         .requires("rpc")
+                         .conflicts_with("lines"))
+                    .arg(Arg::with_name("lines")
+                         .help("Send each input line as its own RPC request.")
+                         .long("lines")
+                         .requires("rpc"))
+                    .arg(Arg::with_name("in_flight")
+                         .help("Number of --lines requests waiting for a reply at a time [default: 10]")
+                         .long("in-flight")
+                         .takes_value(true)
+                         .requires("lines"))
+                    .arg(Arg::with_name("unordered")
+                         .help("Output --lines replies as they arrive, instead of in input order.")
+                         .long("unordered")
+                         .requires("lines"))
+                    .arg(Arg::with_name("dry_run")
+                         .help("Print what would be published as JSON, without connecting.")
+                         .long("dry-run"))
                     .arg(Arg::with_name("content_type")
-                         .help("Content type such as application/json. Inferred from filename if possible.")
+                         .help("Content type such as application/json. Inferred from filename or sniffed from the body if possible.")
                          .short("c")
                          .long("content-type")
                          .takes_value(true))
+                    .arg(Arg::with_name("no_sniff")
+                         .help("Do not sniff the content type from the body.")
+                         .long("no-sniff"))
+                    .arg(Arg::with_name("no_validate")
+                         .help("Do not check that JSON/XML bodies parse before publishing.")
+                         .long("no-validate"))
+                    .arg(Arg::with_name("schema")
+                         .help("JSON Schema file to validate the body against.")
+                         .long("schema")
+                         .takes_value(true))
                     .arg(Arg::with_name("priority")
                          .help("Priority basic property")
                          .short("z")
@@ -116,20 +177,64 @@ fn _main() -> Result<(),RbtError> {
                         .short("t")
                         .long("rpctimeout")
                         .default_value(""))
+                    .arg(Arg::with_name("rpc_info")
+                         .help("Print the RPC reply with delivery info (and headers).")
+                         .long("rpc-info")
+                         .requires("rpc")
+                         .conflicts_with("lines"))
+                    .arg(Arg::with_name("replies")
+                         .help("Collect this many RPC replies (or all within the timeout) and print them as a JSON array.")
+                         .long("replies")
+                         .takes_value(true)
+                         .requires("rpc")
+                         .conflicts_with("lines"))
+                    .arg(Arg::with_name("retries")
+                         .help("Publish the RPC request again this many times when there is no reply within the timeout.")
+                         .long("retries")
+                         .takes_value(true)
+                         .conflicts_with("lines"))
+                    .arg(Arg::with_name("retry_backoff")
+                         .help("Milliseconds to wait before the first retry, doubled for each retry after that.")
+                         .long("retry-backoff")
+                         .takes_value(true)
+                         .default_value("500"))
         )
         .subcommand(SubCommand::with_name("subscribe")
                     .about("Subscribe to an exchange")
                     .arg(Arg::with_name("exchange")
-                         .help("Exchange to subscribe to")
+                         .help("Exchange to subscribe to. Repeat for several exchanges.")
                          .short("e")
                          .long("exchange")
                          .takes_value(true)
-                         .required(true))
+                         .multiple(true)
+                         .number_of_values(1)
+                         .required_unless("bind"))
                     .arg(Arg::with_name("routing_key")
-                         .help("Routing key")
+                         .help("Routing key. Repeat for several keys, each bound on every exchange.")
                          .short("r")
                          .long("routing-key")
-                         .takes_value(true))
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("bind")
+                         .help("Bind to exchange:key. Repeat for several bindings.")
+                         .short("b")
+                         .long("bind")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("bind_arg")
+                         .help("Binding argument on the form \"x-match=all\"")
+                         .long("bind-arg")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+                    .arg(Arg::with_name("bind_header")
+                         .help("Header to match on a headers exchange, on the form \"Region: eu\"")
+                         .long("bind-header")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
                     .arg(Arg::with_name("output")
                          .help("Output directory (- is stdout)")
                          .short("o")
@@ -137,13 +242,42 @@ fn _main() -> Result<(),RbtError> {
                          .takes_value(true)
                          .default_value("-"))
                     .arg(Arg::with_name("info")
-                         .help("Include delivery info (and headers).")
+                         .help("Include delivery info (and headers). Same as --format pretty.")
                          .short("i")
-                         .long("info"))
+                         .long("info")
+                         .conflicts_with("format"))
+                    .arg(Arg::with_name("format")
+                         .help("How to print messages: the body bytes unchanged (raw), the -i envelope (pretty), the envelope on one line (jsonl), the body as hex (hexdump) or --template.")
+                         .long("format")
+                         .takes_value(true)
+                         .possible_values(&["raw", "pretty", "jsonl", "hexdump", "template"]))
+                    .arg(Arg::with_name("template")
+                         .help("Template for --format template, such as '{{deliver.routing_key}} {{body}}'.")
+                         .long("template")
+                         .takes_value(true)
+                         .required_if("format", "template"))
                     .arg(Arg::with_name("single")
                          .help("Expect one single message, then quit.")
                          .short("1")
-                         .long("single"))
+                         .long("single")
+                         .conflicts_with("count"))
+                    .arg(Arg::with_name("count")
+                         .help("Quit after this many messages.")
+                         .long("count")
+                         .takes_value(true))
+                    .arg(Arg::with_name("idle_timeout")
+                         .help("Quit when no message arrived for this long, such as 500ms, 5s or 2m.")
+                         .long("idle-timeout")
+                         .takes_value(true))
+                    .arg(Arg::with_name("drain")
+                         .help("Quit once the messages that were in the queue at start are consumed.")
+                         .long("drain"))
+                    .arg(Arg::with_name("filter")
+                         .help("Only output messages matching, such as '.status == \"failed\"' or 'routing_key ~ orders.*'. Repeat and all must match.")
+                         .long("filter")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
                     .arg(Arg::with_name("queue")
                          .help("Use a named (non-auto_delete) queue.")
                          .takes_value(true)
@@ -157,6 +291,156 @@ fn _main() -> Result<(),RbtError> {
                          .help("Do not automatically acknowledge received messages. (useful for peeking contents of an existing queue)")
                          .short("n")
                          .long("noack"))
+                    .arg(Arg::with_name("offset")
+                         .help("Where to start reading a stream queue: first, last, next, an offset or a time such as 2024-05-01T12:00:00Z.")
+                         .long("offset")
+                         .takes_value(true)
+                         .requires("queue"))
+                    .arg(Arg::with_name("checkpoint")
+                         .help("File to keep the last processed stream offset in, and to resume from.")
+                         .long("checkpoint")
+                         .takes_value(true)
+                         .requires("queue"))
+                    .arg(Arg::with_name("prefetch")
+                         .help("Max number of unacked messages the broker sends at a time.")
+                         .long("prefetch")
+                         .takes_value(true))
+                    .arg(Arg::with_name("prefetch_global")
+                         .help("Apply --prefetch to the whole channel instead of per consumer.")
+                         .long("prefetch-global")
+                         .requires("prefetch"))
+                    .arg(Arg::with_name("on_error")
+                         .help("When a message can't be output: exit, or log it and skip (ack), nack, requeue or dead-letter it.")
+                         .long("on-error")
+                         .takes_value(true)
+                         .possible_values(&["exit", "skip", "nack", "requeue", "dead-letter"])
+                         .default_value("exit"))
+                    .arg(Arg::with_name("dead_letter_to")
+                         .help("Exchange --on-error dead-letter publishes failed messages to. Without it they are nacked to the queue's dead letter exchange.")
+                         .long("dead-letter-to")
+                         .takes_value(true))
+                    .arg(Arg::with_name("dead_letter_exchange")
+                         .help("Declare the queue with a dead letter exchange (x-dead-letter-exchange).")
+                         .long("dead-letter-exchange")
+                         .takes_value(true))
+                    .arg(Arg::with_name("durable")
+                         .help("Declare the queue as durable.")
+                         .long("durable"))
+                    .arg(Arg::with_name("exclusive")
+                         .help("Declare the queue as exclusive to this connection.")
+                         .long("exclusive"))
+                    .arg(Arg::with_name("auto_delete")
+                         .help("Declare the queue as auto-delete.")
+                         .long("auto-delete"))
+                    .arg(Arg::with_name("queue_type")
+                         .help("Type of queue to declare (x-queue-type).")
+                         .long("queue-type")
+                         .takes_value(true)
+                         .possible_values(&["classic", "quorum", "stream"]))
+                    .arg(Arg::with_name("message_ttl")
+                         .help("Milliseconds a message may stay in the queue (x-message-ttl).")
+                         .long("message-ttl")
+                         .takes_value(true))
+                    .arg(Arg::with_name("max_length")
+                         .help("Max number of messages in the queue (x-max-length).")
+                         .long("max-length")
+                         .takes_value(true))
+                    .arg(Arg::with_name("single_active_consumer")
+                         .help("Declare the queue with a single active consumer (x-single-active-consumer).")
+                         .long("single-active-consumer"))
+                    .arg(Arg::with_name("queue_arg")
+                         .help("Other queue argument on the form \"x-max-priority=10\"")
+                         .long("queue-arg")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1))
+        )
+        .subcommand(SubCommand::with_name("get")
+                    .about("Look at messages in a queue with basic.get, without a consumer")
+                    .arg(Arg::with_name("queue")
+                         .help("Queue to get messages from.")
+                         .takes_value(true)
+                         .short("q")
+                         .long("queue")
+                         .required(true))
+                    .arg(Arg::with_name("count")
+                         .help("Max number of messages to get.")
+                         .long("count")
+                         .takes_value(true)
+                         .default_value("1"))
+                    .arg(Arg::with_name("requeue")
+                         .help("Put the messages back on the queue (default).")
+                         .long("requeue"))
+                    .arg(Arg::with_name("ack")
+                         .help("Ack the messages, removing them from the queue.")
+                         .long("ack")
+                         .conflicts_with("requeue"))
+        )
+        .subcommand(SubCommand::with_name("serve-rpc")
+                    .about("Answer RPC requests by running a command per request")
+                    .arg(Arg::with_name("exec")
+                         .help("Command to run. Gets the body on stdin, properties and headers as AMQP_* environment variables. stdout is the reply.")
+                         .short("x")
+                         .long("exec")
+                         .takes_value(true)
+                         .required(true))
+                    .arg(Arg::with_name("queue")
+                         .help("Queue to take requests from.")
+                         .takes_value(true)
+                         .short("q")
+                         .long("queue"))
+                    .arg(Arg::with_name("declare")
+                         .help("Force the declaration of a named queue. Default is to assume the queue is already declared")
+                         .short("d")
+                         .long("declare"))
+                    .arg(Arg::with_name("exchange")
+                         .help("Exchange to take requests from")
+                         .short("e")
+                         .long("exchange")
+                         .takes_value(true))
+                    .arg(Arg::with_name("routing_key")
+                         .help("Routing key")
+                         .short("r")
+                         .long("routing-key")
+                         .takes_value(true))
+                    .arg(Arg::with_name("content_type")
+                         .help("Content type of the replies. Sniffed from the output if not given.")
+                         .short("c")
+                         .long("content-type")
+                         .takes_value(true))
+                    .arg(Arg::with_name("on_fail")
+                         .help("When the command exits non-zero, send its stderr as an error reply, or nack the request.")
+                         .long("on-fail")
+                         .takes_value(true)
+                         .possible_values(&["reply", "nack"])
+                         .default_value("reply"))
+        )
+        .subcommand(SubCommand::with_name("mock")
+                    .about("Answer RPC requests according to rules with fixtures and templates")
+                    .arg(Arg::with_name("rules")
+                         .help("JSON file with rules to match requests and how to reply.")
+                         .long("rules")
+                         .takes_value(true)
+                         .required(true))
+                    .arg(Arg::with_name("queue")
+                         .help("Queue to take requests from.")
+                         .takes_value(true)
+                         .short("q")
+                         .long("queue"))
+                    .arg(Arg::with_name("declare")
+                         .help("Force the declaration of a named queue. Default is to assume the queue is already declared")
+                         .short("d")
+                         .long("declare"))
+                    .arg(Arg::with_name("exchange")
+                         .help("Exchange to take requests from")
+                         .short("e")
+                         .long("exchange")
+                         .takes_value(true))
+                    .arg(Arg::with_name("routing_key")
+                         .help("Routing key")
+                         .short("r")
+                         .long("routing-key")
+                         .takes_value(true))
         )
         .get_matches();
 
@@ -220,6 +504,36 @@ fn _main() -> Result<(),RbtError> {
 
         },
 
+        // execute get command
+        Some("get") => {
+
+            // the args after the "get" command
+            let subm = matches.subcommand_matches("get").unwrap();
+
+            get::do_get(opts, subm)
+
+        },
+
+        // execute serve-rpc command
+        Some("serve-rpc") => {
+
+            // the args after the "serve-rpc" command
+            let subm = matches.subcommand_matches("serve-rpc").unwrap();
+
+            serve::do_serve_rpc(opts, subm)
+
+        },
+
+        // execute mock command
+        Some("mock") => {
+
+            // the args after the "mock" command
+            let subm = matches.subcommand_matches("mock").unwrap();
+
+            mock::do_mock(opts, subm)
+
+        },
+
         _ => rbterr!("Need subcommand. Try --help"),
     }
 
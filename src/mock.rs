@@ -0,0 +1,386 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use rand::{thread_rng, Rng};
+use rustc_serialize::json::{Json, Object};
+use amqp::protocol::basic::{Deliver, BasicProperties};
+use amqp::{self, Basic, Channel, Table, TableEntry};
+use clap::ArgMatches;
+use mime;
+use client;
+use error::RbtError;
+use glob;
+use jsonpath;
+use output;
+use sniff;
+use template;
+
+
+// what a rule replies with
+enum ReplyBody {
+    Fixed(Vec<u8>),
+    Template(String),
+}
+
+struct Rule {
+    routing_key: Option<String>,  // glob
+    headers: Vec<(String, Json)>,
+    body: Vec<(String, Json)>,    // json path -> expected value
+    reply: ReplyBody,
+    content_type: Option<String>,
+    reply_headers: Table,
+    delay: u64,                   // millis
+    drop: f64,                    // probability of not replying
+}
+
+
+pub fn do_mock(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtError> {
+
+    let rules_file = value_t!(matches, "rules", String)?;
+    let rules = read_rules(&rules_file)?;
+    let queue : Option<String> = matches.value_of("queue").map(str::to_owned);
+    let declare = match matches.is_present("declare") {
+        true  => Some(client::QueueDeclare::default()),
+        false => None,
+    };
+    let exchange = matches.value_of("exchange").unwrap_or("").to_owned();
+
+    if queue.is_none() && exchange == "" {
+        rbterr!("Need a queue (-q) or an exchange (-e) to mock");
+    }
+
+    let receive = move |channel: &mut Channel, deliver:Deliver, props:BasicProperties, body:Vec<u8>| ->
+        Result<(),RbtError> {
+
+        let delivery_tag = deliver.delivery_tag;
+        let msg = output::message_json(&deliver, &props, &body);
+
+        let rule = match rules.iter().find(|r| r.matches(&msg)) {
+            Some(r) => r,
+            None => {
+                errln!("Request {}: no rule matches, not replying", delivery_tag);
+                channel.basic_ack(delivery_tag, false)?;
+                return Ok(());
+            }
+        };
+
+        let reply_to = match props.reply_to.clone() {
+            Some(r) => r,
+            None => {
+                errln!("Request {} has no reply_to, not replying", delivery_tag);
+                channel.basic_ack(delivery_tag, false)?;
+                return Ok(());
+            }
+        };
+
+        // this sleeps on the consumer thread, so the requests after
+        // this one wait too. run more mocks on the same -q to overlap.
+        if rule.delay > 0 {
+            thread::sleep(Duration::from_millis(rule.delay));
+        }
+
+        if rule.drop > 0.0 && thread_rng().gen::<f64>() < rule.drop {
+            errln!("Request {}: dropped", delivery_tag);
+            channel.basic_ack(delivery_tag, false)?;
+            return Ok(());
+        }
+
+        let reply_body = match rule.reply {
+            ReplyBody::Fixed(ref b) => b.clone(),
+            ReplyBody::Template(ref t) => template::render(t, &msg).into_bytes(),
+        };
+
+        let content_type = rule.content_type.clone()
+            .unwrap_or_else(|| sniff::sniff(&reply_body).to_owned());
+
+        let reply_props = BasicProperties {
+            content_type: Some(content_type),
+            correlation_id: props.correlation_id.clone(),
+            headers: Some(rule.reply_headers.clone()),
+            ..Default::default()
+        };
+
+        channel.basic_publish("".to_owned(), reply_to, false, false, reply_props, reply_body)?;
+        channel.basic_ack(delivery_tag, false)?;
+
+        Ok(())
+    };
+
+    let receiver = client::Receiver {
+        bindings: vec![client::Binding {
+            exchange: exchange,
+            routing_key: matches.value_of("routing_key").map(str::to_owned),
+            arguments: Table::new(),
+        }],
+        auto_ack: false, // after the reply is sent
+        on_error: client::OnError::Exit,
+        failures: client::Failures::default(),
+        prefetch: None,
+        arguments: Table::new(),
+        correlation_id: None,
+        filter: None,
+        callback: Box::new(receive),
+    };
+
+    client::open_receive(opts, queue, declare, client::Limits::default(), receiver)
+}
+
+
+impl Rule {
+
+    fn matches(&self, msg:&Json) -> bool {
+
+        if let Some(ref pattern) = self.routing_key {
+            let key = jsonpath::lookup(msg, ".deliver.routing_key")
+                .and_then(|k| k.as_string()).unwrap_or("");
+            if !glob::glob_match(pattern, key) {
+                return false;
+            }
+        }
+
+        let headers = jsonpath::lookup(msg, ".props.headers").and_then(|h| h.as_object());
+        for &(ref name, ref expected) in &self.headers {
+            match headers.and_then(|h| h.get(name)) {
+                Some(actual) if jsonpath::value_matches(expected, actual) => (),
+                _ => return false,
+            }
+        }
+
+        let body = jsonpath::lookup(msg, ".body").unwrap();
+        for &(ref path, ref expected) in &self.body {
+            match jsonpath::lookup(body, path) {
+                Some(actual) if jsonpath::value_matches(expected, actual) => (),
+                _ => return false,
+            }
+        }
+
+        true
+    }
+}
+
+
+// the rules file is an array of rules, or an object with "rules"
+fn read_rules(file:&str) -> Result<Vec<Rule>,RbtError> {
+
+    let mut reader = fs::File::open(file)?;
+    let json = Json::from_reader(&mut reader)
+        .map_err(|e| format!("Failed to read rules {}: {}", file, e))?;
+
+    let list = match json {
+        Json::Array(a) => a,
+        Json::Object(mut o) => match o.remove("rules") {
+            Some(Json::Array(a)) => a,
+            _ => rbterr!("Rules {} must be an array, or an object with \"rules\"", file),
+        },
+        _ => rbterr!("Rules {} must be an array, or an object with \"rules\"", file),
+    };
+
+    // fixture files are relative to the rules file
+    let dir = Path::new(file).parent().unwrap_or(Path::new(""));
+    let types = mime::Types::new().or(Err("Failed to read mime types"))?;
+
+    let mut rules = vec![];
+    for (i, r) in list.iter().enumerate() {
+        let rule = parse_rule(r, dir, &types)
+            .map_err(|e| format!("Rule {} in {}: {}", i + 1, file, e))?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+
+fn parse_rule(r:&Json, dir:&Path, types:&mime::Types) -> Result<Rule,String> {
+
+    let empty = Object::new();
+    let m = match r.find("match") {
+        Some(m) => m.as_object().ok_or("\"match\" must be an object")?,
+        None => &empty,
+    };
+    let reply = r.find("reply").and_then(|r| r.as_object()).ok_or("needs a \"reply\" object")?;
+
+    let routing_key = match m.get("routing_key") {
+        Some(k) => Some(k.as_string().ok_or("\"routing_key\" must be a string")?.to_owned()),
+        None => None,
+    };
+
+    let pairs = |name:&str| -> Result<Vec<(String, Json)>,String> {
+        match m.get(name) {
+            Some(&Json::Object(ref o)) => Ok(o.iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
+            Some(_) => Err(format!("\"{}\" must be an object", name)),
+            None => Ok(vec![]),
+        }
+    };
+    let headers = pairs("headers")?;
+    let body = pairs("body")?;
+    for &(ref path, _) in &body {
+        jsonpath::check(path)?;
+    }
+
+    let mut content_type = match reply.get("content_type") {
+        Some(c) => Some(c.as_string().ok_or("\"content_type\" must be a string")?.to_owned()),
+        None => None,
+    };
+
+    let reply_body = if let Some(f) = reply.get("file") {
+        let f = f.as_string().ok_or("\"file\" must be a string")?;
+        let path = dir.join(f);
+        let data = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if content_type.is_none() {
+            content_type = path.extension()
+                .and_then(|e| e.to_str())
+                .and_then(|e| types.get_mime_type(e))
+                .map(str::to_owned);
+        }
+        ReplyBody::Fixed(data)
+    } else if let Some(t) = reply.get("template") {
+        let t = t.as_string().ok_or("\"template\" must be a string")?;
+        template::check(t)?;
+        ReplyBody::Template(t.to_owned())
+    } else if let Some(b) = reply.get("body") {
+        // inline JSON
+        if content_type.is_none() {
+            content_type = Some("application/json".to_owned());
+        }
+        ReplyBody::Fixed(b.to_string().into_bytes())
+    } else {
+        return Err("reply needs a \"file\", \"template\" or \"body\"".to_owned());
+    };
+
+    let mut reply_headers = Table::new();
+    match reply.get("headers") {
+        Some(&Json::Object(ref o)) => for (k, v) in o {
+            reply_headers.insert(k.clone(), json_to_entry(v));
+        },
+        Some(_) => return Err("\"headers\" must be an object".to_owned()),
+        None => (),
+    }
+    if let Some(status) = reply.get("status") {
+        let status = status.as_i64().ok_or("\"status\" must be an integer")?;
+        reply_headers.insert("status".to_owned(), TableEntry::LongLongInt(status));
+    }
+
+    let delay = match reply.get("delay") {
+        Some(d) => d.as_u64().ok_or("\"delay\" must be milliseconds")?,
+        None => 0,
+    };
+    let drop = match reply.get("drop") {
+        Some(d) => d.as_f64().ok_or("\"drop\" must be a probability 0.0-1.0")?,
+        None => 0.0,
+    };
+
+    Ok(Rule {
+        routing_key: routing_key,
+        headers: headers,
+        body: body,
+        reply: reply_body,
+        content_type: content_type,
+        reply_headers: reply_headers,
+        delay: delay,
+        drop: drop,
+    })
+}
+
+
+fn json_to_entry(j:&Json) -> TableEntry {
+    match *j {
+        Json::Boolean(v)       => TableEntry::Bool(v),
+        Json::I64(v)           => TableEntry::LongLongInt(v),
+        Json::U64(v)           => TableEntry::LongLongUint(v),
+        Json::F64(v)           => TableEntry::Double(v),
+        Json::String(ref v)    => TableEntry::LongString(v.clone()),
+        Json::Null             => TableEntry::Void,
+        Json::Array(ref vs)    => TableEntry::FieldArray(vs.iter().map(json_to_entry).collect()),
+        Json::Object(ref o)    => {
+            let mut t = Table::new();
+            for (k, v) in o {
+                t.insert(k.clone(), json_to_entry(v));
+            }
+            TableEntry::FieldTable(t)
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(json:&str) -> Result<Rule,String> {
+        let types = mime::Types::new().unwrap();
+        parse_rule(&Json::from_str(json).unwrap(), Path::new(""), &types)
+    }
+
+    fn ok(json:&str) -> Rule {
+        rule(json).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn msg(routing_key:&str, headers:&str, body:&str) -> Json {
+        Json::from_str(&format!(r#"{{"deliver": {{"routing_key": "{}"}},
+                                     "props": {{"headers": {}}}, "body": {}}}"#,
+                                routing_key, headers, body)).unwrap()
+    }
+
+    #[test]
+    fn match_all() {
+        let r = ok(r#"{"reply": {"body": {"ok": true}}}"#);
+        assert!(r.matches(&msg("anything", "{}", "null")));
+        assert_eq!(r.content_type, Some("application/json".to_owned()));
+        match r.reply {
+            ReplyBody::Fixed(ref b) => assert_eq!(b, b"{\"ok\":true}"),
+            _ => panic!("expected a fixed body"),
+        }
+    }
+
+    #[test]
+    fn routing_keys() {
+        let r = ok(r#"{"match": {"routing_key": "orders.*"}, "reply": {"body": 1}}"#);
+        assert!(r.matches(&msg("orders.eu", "{}", "null")));
+        assert!(!r.matches(&msg("payments.eu", "{}", "null")));
+    }
+
+    #[test]
+    fn headers() {
+        let r = ok(r#"{"match": {"headers": {"region": "eu", "retry": "true"}}, "reply": {"body": 1}}"#);
+        assert!(r.matches(&msg("x", r#"{"region": "eu", "retry": true}"#, "null")));
+        assert!(!r.matches(&msg("x", r#"{"region": "us", "retry": true}"#, "null")));
+        assert!(!r.matches(&msg("x", r#"{"region": "eu"}"#, "null")));
+    }
+
+    #[test]
+    fn body_paths() {
+        let r = ok(r#"{"match": {"body": {".order.status": "failed", ".order.items[0]": 3}},
+                       "reply": {"body": 1}}"#);
+        assert!(r.matches(&msg("x", "{}", r#"{"order": {"status": "failed", "items": [3.0]}}"#)));
+        assert!(!r.matches(&msg("x", "{}", r#"{"order": {"status": "ok", "items": [3]}}"#)));
+        assert!(!r.matches(&msg("x", "{}", r#""not json""#)));
+    }
+
+    #[test]
+    fn reply_options() {
+        let r = ok(r#"{"reply": {"template": "{{body}}", "content_type": "text/plain",
+                                 "headers": {"a": "b"}, "status": 500, "delay": 250, "drop": 0.5}}"#);
+        assert_eq!(r.content_type, Some("text/plain".to_owned()));
+        assert_eq!(r.reply_headers.get("status"), Some(&TableEntry::LongLongInt(500)));
+        assert_eq!(r.reply_headers.get("a"), Some(&TableEntry::LongString("b".to_owned())));
+        assert_eq!(r.delay, 250);
+        assert_eq!(r.drop, 0.5);
+        match r.reply {
+            ReplyBody::Template(ref t) => assert_eq!(t, "{{body}}"),
+            _ => panic!("expected a template"),
+        }
+    }
+
+    #[test]
+    fn bad_rules() {
+        let err = |json:&str| rule(json).err().expect(json);
+        assert_eq!(err(r#"{}"#), "needs a \"reply\" object");
+        assert_eq!(err(r#"{"reply": {}}"#), "reply needs a \"file\", \"template\" or \"body\"");
+        assert_eq!(err(r#"{"match": [], "reply": {"body": 1}}"#), "\"match\" must be an object");
+        assert_eq!(err(r#"{"match": {"routing_key": 1}, "reply": {"body": 1}}"#),
+                   "\"routing_key\" must be a string");
+        assert_eq!(err(r#"{"match": {"body": {"order": 1}}, "reply": {"body": 1}}"#), "Bad path: order");
+        assert_eq!(err(r#"{"reply": {"body": 1, "status": "500"}}"#), "\"status\" must be an integer");
+        assert_eq!(err(r#"{"reply": {"body": 1, "delay": -1}}"#), "\"delay\" must be milliseconds");
+    }
+}
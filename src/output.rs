@@ -1,66 +1,115 @@
-use rustc_serialize::json::{self, Json, Object};
+use rustc_serialize::json::{self, Json, Object, ToJson};
 use rustc_serialize::base64::{self, ToBase64};
+use rustc_serialize::{Encodable, Encoder};
 use amqp::protocol::basic::{Deliver, BasicProperties};
 use amqp::{Table, TableEntry};
 use error::RbtError;
+use validate;
+use template;
+use iso8601;
+use std::str;
 
 
-#[derive(RustcEncodable)]
 struct MsgDeliver {
     consumer_tag: String,
     delivery_tag: u64,
     redelivered: bool,
     exchange: String,
     routing_key: String,
+    // position in a stream queue
+    stream_offset: Option<i64>,
 }
 
-#[derive(RustcEncodable)]
-struct MsgProps {
-    content_type: String,
-    headers: Object,
+// like the derived one, but stream_offset is left out when there
+// is none rather than null.
+impl Encodable for MsgDeliver {
+    fn encode<S:Encoder>(&self, s:&mut S) -> Result<(), S::Error> {
+        let len = if self.stream_offset.is_some() { 6 } else { 5 };
+        s.emit_struct("MsgDeliver", len, |s| {
+            s.emit_struct_field("consumer_tag", 0, |s| self.consumer_tag.encode(s))?;
+            s.emit_struct_field("delivery_tag", 1, |s| self.delivery_tag.encode(s))?;
+            s.emit_struct_field("redelivered", 2, |s| self.redelivered.encode(s))?;
+            s.emit_struct_field("exchange", 3, |s| self.exchange.encode(s))?;
+            s.emit_struct_field("routing_key", 4, |s| self.routing_key.encode(s))?;
+            if let Some(offset) = self.stream_offset {
+                s.emit_struct_field("stream_offset", 5, |s| offset.encode(s))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+// for the envelopes that are put together as Json
+impl ToJson for MsgDeliver {
+    fn to_json(&self) -> Json {
+        let mut obj = Object::new();
+        obj.insert("consumer_tag".to_owned(), Json::String(self.consumer_tag.clone()));
+        obj.insert("delivery_tag".to_owned(), Json::U64(self.delivery_tag));
+        obj.insert("redelivered".to_owned(), Json::Boolean(self.redelivered));
+        obj.insert("exchange".to_owned(), Json::String(self.exchange.clone()));
+        obj.insert("routing_key".to_owned(), Json::String(self.routing_key.clone()));
+        if let Some(offset) = self.stream_offset {
+            obj.insert("stream_offset".to_owned(), Json::I64(offset));
+        }
+        Json::Object(obj)
+    }
 }
 
 #[derive(RustcEncodable)]
 struct Msg {
     deliver: MsgDeliver,
-    props: MsgProps,
+    props: Object,
     data: Json,
 }
 
-pub fn build_output(info:bool, deliver:&Deliver,
-                    props:&BasicProperties, body:Vec<u8>) -> Result<Vec<u8>,RbtError> {
-    if info {
+// the envelope of a basic.get, with the messages left in the queue
+#[derive(RustcEncodable)]
+struct GetMsg {
+    deliver: MsgDeliver,
+    props: Object,
+    data: Json,
+    message_count: u32,
+}
 
-        // delivery info
-        let mdel = MsgDeliver {
-            consumer_tag:deliver.consumer_tag.clone(),
-            delivery_tag:deliver.delivery_tag.clone(),
-            redelivered:deliver.redelivered.clone(),
-            exchange:deliver.exchange.clone(),
-            routing_key:deliver.routing_key.clone(),
-        };
+// the delivery info of the envelope
+fn build_deliver(deliver:&Deliver, props:&BasicProperties) -> MsgDeliver {
+    MsgDeliver {
+        consumer_tag:deliver.consumer_tag.clone(),
+        delivery_tag:deliver.delivery_tag.clone(),
+        redelivered:deliver.redelivered.clone(),
+        exchange:deliver.exchange.clone(),
+        routing_key:deliver.routing_key.clone(),
+        stream_offset:stream_offset(props),
+    }
+}
 
-        let content_type = props.content_type.clone().unwrap_or(String::from(""));
+// the envelope of -i
+fn build_msg(deliver:&Deliver, props:&BasicProperties, body:Vec<u8>) -> Result<Msg,RbtError> {
 
-        // properties
-        let mut mprops = MsgProps {
-            content_type:content_type.clone(),
-            headers: Object::new(),
-        };
+    // delivery info
+    let mdel = build_deliver(deliver, props);
 
-        if let Some(ref table) = props.headers {
-            mprops.headers = table_to_json(table);
-        }
+    let content_type = props.content_type.clone().unwrap_or(String::from(""));
 
-        // the body
-        let data = figure_out_body(content_type, body)?;
+    // properties
+    let mprops = props_to_json(props);
 
-        // and put it together
-        let msg = Msg {
-            deliver:mdel,
-            props:mprops,
-            data:data,
-        };
+    // the body
+    let data = figure_out_body(content_type, body)?;
+
+    // and put it together
+    Ok(Msg {
+        deliver:mdel,
+        props:mprops,
+        data:data,
+    })
+}
+
+pub fn build_output(info:bool, deliver:&Deliver,
+                    props:&BasicProperties, body:Vec<u8>) -> Result<Vec<u8>,RbtError> {
+    if info {
+
+        let msg = build_msg(deliver, props, body)?;
 
         // encode
         let js = json::as_pretty_json(&msg);
@@ -92,6 +141,205 @@ pub fn build_output(info:bool, deliver:&Deliver,
 }
 
 
+// how subscribe prints a message
+pub enum Format {
+    // the body, JSON pretty printed
+    Body,
+    // the body bytes as they came
+    Raw,
+    // the -i envelope
+    Pretty,
+    // the -i envelope, compact on one line
+    Jsonl,
+    // the body as hexdump -C
+    Hexdump,
+    // a template over message_json
+    Template(String),
+}
+
+pub fn build_formatted(format:&Format, deliver:&Deliver,
+                       props:&BasicProperties, body:Vec<u8>) -> Result<Vec<u8>,RbtError> {
+    match *format {
+        Format::Body    => build_output(false, deliver, props, body),
+        Format::Raw     => Ok(body),
+        Format::Pretty  => build_output(true, deliver, props, body),
+        Format::Jsonl   => {
+            let msg = build_msg(deliver, props, body)?;
+            Ok(json::as_json(&msg).to_string().as_bytes().to_owned())
+        },
+        Format::Hexdump => Ok(hexdump(&body).into_bytes()),
+        Format::Template(ref tpl) =>
+            Ok(template::render(tpl, &message_json(deliver, props, &body)).into_bytes()),
+    }
+}
+
+// 16 bytes a line, offset, hex and ascii, like hexdump -C
+fn hexdump(body:&[u8]) -> String {
+    let mut out = String::new();
+    for (i, chunk) in body.chunks(16).enumerate() {
+        out.push_str(&format!("{:08x} ", i * 16));
+        for j in 0..16 {
+            if j == 8 {
+                out.push(' ');
+            }
+            match chunk.get(j) {
+                Some(b) => out.push_str(&format!(" {:02x}", b)),
+                None    => out.push_str("   "),
+            }
+        }
+        let ascii:String = chunk.iter()
+            .map(|&b| if b >= 0x20 && b < 0x7f { b as char } else { '.' })
+            .collect();
+        out.push_str(&format!("  |{}|\n", ascii));
+    }
+    out.push_str(&format!("{:08x}", body.len()));
+    out
+}
+
+
+// the properties of a message. content_type and headers are always
+// there, the rest only when they are set. the timestamp is also given
+// as ISO-8601 in timestamp_iso.
+fn props_to_json(props:&BasicProperties) -> Object {
+
+    let mut obj = Object::new();
+    obj.insert("content_type".to_owned(),
+               Json::String(props.content_type.clone().unwrap_or(String::from(""))));
+    obj.insert("headers".to_owned(),
+               Json::Object(props.headers.as_ref().map(table_to_json).unwrap_or(Object::new())));
+
+    {
+        let mut string = |name:&str, value:&Option<String>| {
+            if let Some(ref v) = *value {
+                obj.insert(name.to_owned(), Json::String(v.clone()));
+            }
+        };
+        string("content_encoding", &props.content_encoding);
+        string("correlation_id", &props.correlation_id);
+        string("reply_to", &props.reply_to);
+        string("expiration", &props.expiration);
+        string("message_id", &props.message_id);
+        string("type", &props._type);
+        string("user_id", &props.user_id);
+        string("app_id", &props.app_id);
+        string("cluster_id", &props.cluster_id);
+    }
+
+    if let Some(v) = props.delivery_mode {
+        obj.insert("delivery_mode".to_owned(), Json::U64(v as u64));
+    }
+    if let Some(v) = props.priority {
+        obj.insert("priority".to_owned(), Json::U64(v as u64));
+    }
+    if let Some(t) = props.timestamp {
+        obj.insert("timestamp".to_owned(), Json::U64(t));
+        obj.insert("timestamp_iso".to_owned(), Json::String(iso8601::format(t)));
+    }
+
+    obj
+}
+
+
+// the x-stream-offset header of messages from a stream queue
+pub fn stream_offset(props:&BasicProperties) -> Option<i64> {
+    let headers = props.headers.as_ref()?;
+    match entry_to_json(headers.get("x-stream-offset")?) {
+        Json::I64(v) => Some(v),
+        Json::U64(v) => Some(v as i64),
+        _ => None,
+    }
+}
+
+
+pub fn build_get_output(deliver:&Deliver, props:&BasicProperties, body:Vec<u8>,
+                        message_count:u32) -> Result<Vec<u8>,RbtError> {
+
+    let msg = build_msg(deliver, props, body)?;
+    let msg = GetMsg {
+        deliver:msg.deliver,
+        props:msg.props,
+        data:msg.data,
+        message_count:message_count,
+    };
+
+    Ok(json::as_pretty_json(&msg).to_string().as_bytes().to_owned())
+}
+
+
+// the message as one JSON value, for templates and matching rules:
+// {"deliver":{...},"props":{...},"body":...}. a body that doesn't
+// decode according to its content type is a (lossy) string.
+pub fn message_json(deliver:&Deliver, props:&BasicProperties, body:&[u8]) -> Json {
+
+    let content_type = props.content_type.clone().unwrap_or(String::from(""));
+
+    let data = if validate::is_json(&content_type) {
+        validate::parse_json(body).ok()
+    } else {
+        figure_out_body(content_type, body.to_vec()).ok()
+    };
+    let data = data.unwrap_or_else(|| Json::String(String::from_utf8_lossy(body).into_owned()));
+
+    let mut msg = Object::new();
+    msg.insert("deliver".to_owned(), build_deliver(deliver, props).to_json());
+    msg.insert("props".to_owned(), Json::Object(props_to_json(props)));
+    msg.insert("body".to_owned(), data);
+    Json::Object(msg)
+}
+
+
+// one line of batch rpc output, with the reply data or the error
+// for the request on the given input line.
+pub fn build_batch_line(line:usize, correlation_id:&str,
+                        reply:Result<(BasicProperties, Vec<u8>),String>) -> Vec<u8> {
+
+    let data = reply.and_then(|(props, body)| {
+        let content_type = props.content_type.clone().unwrap_or(String::from(""));
+        figure_out_body(content_type, body).map_err(|e| e.to_string())
+    });
+
+    let mut obj = Object::new();
+    obj.insert("line".to_owned(), Json::U64(line as u64));
+    obj.insert("correlation_id".to_owned(), Json::String(correlation_id.to_owned()));
+    match data {
+        Ok(d)  => obj.insert("data".to_owned(), d),
+        Err(e) => obj.insert("error".to_owned(), Json::String(e)),
+    };
+
+    json::as_json(&Json::Object(obj)).to_string().as_bytes().to_owned()
+}
+
+
+// the replies to a scatter-gather rpc as a JSON array, each tagged
+// with who sent it, the app_id or else the reply_to of the reply.
+pub fn build_gathered(info:bool,
+                      replies:Vec<(Deliver, BasicProperties, Vec<u8>)>) -> Result<Vec<u8>,RbtError> {
+
+    let mut arr = vec![];
+
+    for (deliver, props, body) in replies {
+
+        let from = props.app_id.clone().or(props.reply_to.clone())
+            .map(Json::String).unwrap_or(Json::Null);
+
+        let msg = build_msg(&deliver, &props, body)?;
+
+        let mut obj = Object::new();
+        obj.insert("from".to_owned(), from);
+
+        if info {
+            obj.insert("deliver".to_owned(), msg.deliver.to_json());
+            obj.insert("props".to_owned(), Json::Object(msg.props));
+        }
+
+        obj.insert("data".to_owned(), msg.data);
+        arr.push(Json::Object(obj));
+    }
+
+    Ok(json::as_pretty_json(&Json::Array(arr)).to_string().as_bytes().to_owned())
+}
+
+
 fn figure_out_body(content_type:String, body:Vec<u8>) -> Result<Json,RbtError> {
 
     // depending on content type, do something
@@ -107,7 +355,109 @@ fn figure_out_body(content_type:String, body:Vec<u8>) -> Result<Json,RbtError> {
 
 
 
-fn table_to_json(table:&Table) -> Object {
+// how many characters of the body to show in a dry run
+const PREVIEW_LEN:usize = 200;
+
+// describe a message we would publish, with the AMQP
+// field-table types of each property and header.
+pub fn describe_publish(exchange:&str, routing_key:&str, rpc:bool,
+                        props:&BasicProperties, body:&[u8]) -> Vec<u8> {
+
+    let mut desc = Object::new();
+    desc.insert("exchange".to_owned(), Json::String(exchange.to_owned()));
+    desc.insert("routing_key".to_owned(), Json::String(routing_key.to_owned()));
+    desc.insert("rpc".to_owned(), Json::Boolean(rpc));
+
+    // the basic properties have their own domain types
+    let mut mprops = Object::new();
+    {
+        let mut put = |name:&str, typ:&str, value:Option<Json>| {
+            if let Some(v) = value {
+                mprops.insert(name.to_owned(), typed(typ, v));
+            }
+        };
+        let short = |v:&Option<String>| v.clone().map(Json::String);
+        put("content_type", "shortstr", short(&props.content_type));
+        put("content_encoding", "shortstr", short(&props.content_encoding));
+        put("headers", "table", props.headers.as_ref().map(typed_table));
+        put("delivery_mode", "octet", props.delivery_mode.map(|v| Json::U64(v as u64)));
+        put("priority", "octet", props.priority.map(|v| Json::U64(v as u64)));
+        put("correlation_id", "shortstr", short(&props.correlation_id));
+        put("reply_to", "shortstr", short(&props.reply_to));
+        put("expiration", "shortstr", short(&props.expiration));
+        put("message_id", "shortstr", short(&props.message_id));
+        put("timestamp", "timestamp", props.timestamp.map(Json::U64));
+        put("type", "shortstr", short(&props._type));
+        put("user_id", "shortstr", short(&props.user_id));
+        put("app_id", "shortstr", short(&props.app_id));
+        put("cluster_id", "shortstr", short(&props.cluster_id));
+    }
+    desc.insert("props".to_owned(), Json::Object(mprops));
+
+    let mut mbody = Object::new();
+    mbody.insert("size".to_owned(), Json::U64(body.len() as u64));
+    if let Ok(text) = str::from_utf8(body) {
+        let mut preview:String = text.chars().take(PREVIEW_LEN).collect();
+        if preview.len() < text.len() {
+            preview.push_str("...");
+        }
+        mbody.insert("preview".to_owned(), Json::String(preview));
+    }
+    desc.insert("body".to_owned(), Json::Object(mbody));
+
+    json::as_pretty_json(&Json::Object(desc)).to_string().as_bytes().to_owned()
+}
+
+
+fn typed(typ:&str, value:Json) -> Json {
+    let mut ret = Object::new();
+    ret.insert("type".to_owned(), Json::String(typ.to_owned()));
+    ret.insert("value".to_owned(), value);
+    Json::Object(ret)
+}
+
+fn typed_table(table:&Table) -> Json {
+    let mut ret = Object::new();
+    for (skey, entry) in table {
+        ret.insert(skey.clone(), entry_to_typed_json(entry));
+    }
+    Json::Object(ret)
+}
+
+// {"type":"long-string","value":"foo"}, nested for tables and arrays
+fn entry_to_typed_json(entry:&TableEntry) -> Json {
+    let value = match *entry {
+        TableEntry::FieldTable(ref t)  => typed_table(t),
+        TableEntry::FieldArray(ref vs) => Json::Array(vs.iter().map(entry_to_typed_json).collect()),
+        _ => entry_to_json(entry),
+    };
+    typed(entry_type_name(entry), value)
+}
+
+fn entry_type_name(entry:&TableEntry) -> &'static str {
+    match *entry {
+        TableEntry::Bool(_)            => "boolean",
+        TableEntry::ShortShortInt(_)   => "short-short-int",
+        TableEntry::ShortShortUint(_)  => "short-short-uint",
+        TableEntry::ShortInt(_)        => "short-int",
+        TableEntry::ShortUint(_)       => "short-uint",
+        TableEntry::LongInt(_)         => "long-int",
+        TableEntry::LongUint(_)        => "long-uint",
+        TableEntry::LongLongInt(_)     => "long-long-int",
+        TableEntry::LongLongUint(_)    => "long-long-uint",
+        TableEntry::Float(_)           => "float",
+        TableEntry::Double(_)          => "double",
+        TableEntry::DecimalValue(_, _) => "decimal",
+        TableEntry::LongString(_)      => "long-string",
+        TableEntry::FieldArray(_)      => "field-array",
+        TableEntry::Timestamp(_)       => "timestamp",
+        TableEntry::FieldTable(_)      => "field-table",
+        TableEntry::Void               => "void",
+    }
+}
+
+
+pub fn table_to_json(table:&Table) -> Object {
     let mut ret = Object::new();
     for (skey, entry) in table {
         ret.insert(skey.clone().to_string(), entry_to_json(&entry));
@@ -146,3 +496,46 @@ fn entry_to_json(entry:&TableEntry) -> Json {
         // TableEntry::ShortString(ref v) => Ok(Json::String(v.clone())),
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deliver() -> Deliver {
+        Deliver {
+            consumer_tag: "ctag".to_owned(),
+            delivery_tag: 1,
+            redelivered: false,
+            exchange: "x".to_owned(),
+            routing_key: "k".to_owned(),
+        }
+    }
+
+    fn format(format:Format, content_type:&str, body:&[u8]) -> Vec<u8> {
+        let props = BasicProperties { content_type: Some(content_type.to_owned()), ..Default::default() };
+        build_formatted(&format, &deliver(), &props, body.to_vec()).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[test]
+    fn raw_is_unchanged() {
+        assert_eq!(format(Format::Raw, "application/json", b"{\"a\":  1}"), b"{\"a\":  1}".to_vec());
+        assert_eq!(format(Format::Raw, "application/octet-stream", b"\x00\xff"), b"\x00\xff".to_vec());
+    }
+
+    #[test]
+    fn body_pretty_prints_json() {
+        assert_eq!(format(Format::Body, "application/json", b"{\"a\":  1}"), b"{\n  \"a\": 1\n}".to_vec());
+        assert_eq!(format(Format::Body, "text/plain", b"{\"a\":  1}"), b"{\"a\":  1}".to_vec());
+    }
+
+    #[test]
+    fn hexdumps() {
+        assert_eq!(hexdump(b""), "00000000");
+        assert_eq!(hexdump(b"hi\n"), "00000000  68 69 0a                                          |hi.|\n00000003");
+        assert_eq!(hexdump(b"0123456789abcdef\x00"),
+                   "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+                    00000010  00                                                |.|\n\
+                    00000011");
+    }
+}
@@ -1,6 +1,5 @@
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
 use amqp;
-use amqp::protocol::basic::{Deliver, BasicProperties};
 use clap::ArgMatches;
 use std::fs;
 use std::path::Path;
@@ -8,17 +7,92 @@ use mime;
 use client;
 use error::RbtError;
 use output;
-use amqp::{Channel};
-use std::panic;
+use schema;
+use sniff;
+use validate;
+use std::collections::BTreeMap;
 
 
 // helper function to turn a filename
-// into a mime-type
-fn type_from_file(file:&String) -> Result<String,RbtError> {
-    let t = mime::Types::new().or(Err("Failed to read mime types"))?;
+// into a mime-type. if the extension doesn't
+// tell us, we sniff the body.
+fn type_from_file(t:&mime::Types, file:&String, body:&[u8], sniff:bool) -> String {
     let path = Path::new(&file);
-    let mime = t.mime_for_path(&path);
-    Ok(mime.to_owned())
+    if !sniff {
+        return t.mime_for_path(&path).to_owned();
+    }
+    let mime = path.extension()
+        .and_then(|s| s.to_str())
+        .and_then(|ext| t.get_mime_type(ext))
+        .unwrap_or_else(|| sniff::sniff(body));
+    mime.to_owned()
+}
+
+
+// declare or check the exchange, depending on args
+fn exchange_declare(matches:&ArgMatches) -> Option<client::ExchangeDeclare> {
+    let exchange_type = matches.value_of("declare_exchange").map(str::to_owned);
+    if exchange_type.is_none() && !matches.is_present("check_exchange") {
+        return None;
+    }
+    Some(client::ExchangeDeclare {
+        exchange_type: exchange_type,
+        durable:       matches.is_present("durable"),
+        auto_delete:   matches.is_present("auto_delete"),
+    })
+}
+
+
+// figure out the content type of each body we are about to send,
+// and check it. the line numbers are only used in errors with --lines.
+fn check_requests(matches:&ArgMatches, file:&String,
+                  checked:&[(usize, &[u8])]) -> Result<Vec<String>,RbtError> {
+
+    let lines = matches.is_present("lines");
+    let types = mime::Types::new().or(Err("Failed to read mime types"))?;
+    let sniff = !matches.is_present("no_sniff");
+    let mut content_types = vec![];
+
+    let schema = match matches.value_of("schema") {
+        Some(f) => Some((f, schema::Schema::from_file(f)?)),
+        None    => None,
+    };
+
+    for &(n, b) in checked {
+
+        // figure out a good content type, lines may differ
+        let content_type = match matches.value_of("content_type") {
+            Some(c) if c != "-" => c.to_owned(),
+            _ => type_from_file(&types, file, b, sniff),
+        };
+
+        // say which line is broken
+        let at = match lines {
+            true  => format!("line {}: ", n),
+            false => "".to_owned(),
+        };
+
+        // check the body parses before we send it anywhere
+        if !matches.is_present("no_validate") {
+            validate::check_body(&content_type, b).map_err(|e| format!("{}{}", at, e))?;
+        }
+
+        // and optionally that it matches a JSON schema
+        if let Some((schema_file, ref schema)) = schema {
+            let doc = validate::parse_json(b).map_err(|e| format!("{}{}", at, e))?;
+            let errs = schema.validate(&doc).map_err(|e| format!("Bad schema {}: {}", schema_file, e))?;
+            if !errs.is_empty() {
+                for e in errs {
+                    errln!("{}{}", at, e);
+                }
+                rbterr!("{}Body does not match schema {}", at, schema_file);
+            }
+        }
+
+        content_types.push(content_type);
+    }
+
+    Ok(content_types)
 }
 
 
@@ -27,7 +101,7 @@ pub fn do_publish(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtError
     // either stdin or a file
     let file = value_t!(matches, "file", String)?;
     let rpc  = matches.is_present("rpc");
-    let reader: Box<io::Read> = match file.as_ref() {
+    let mut reader: Box<io::Read> = match file.as_ref() {
         "-" => Box::new(io::stdin()),
         _   => Box::new(fs::File::open(&file)?),
     };
@@ -42,14 +116,27 @@ pub fn do_publish(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtError
         }
     };
 
-    // figure out a good content type
-    // XXX should we fall back on binary octet-stream?
-    let content_type = {
-        let c = matches.value_of("content_type").unwrap_or("-").to_string();
-        match c.as_ref() {
-            "-" => type_from_file(&file)?,
-            _   => c,
+    // read input buffer
+    let mut body = vec![];
+    reader.read_to_end(&mut body)?;
+
+    // with --lines, each line is a request of its own
+    let lines = matches.is_present("lines");
+    let requests = match lines {
+        true  => split_lines(&body),
+        false => vec![],
+    };
+
+    // what we check and send, each line or the whole body
+    let content_types = {
+        let checked:Vec<(usize, &[u8])> = match lines {
+            true  => requests.iter().map(|&(n, ref b)| (n, &b[..])).collect(),
+            false => vec![(0, &body[..])],
+        };
+        if checked.is_empty() {
+            rbterr!("No lines to send");
         }
+        check_requests(matches, &file, &checked)?
     };
 
 
@@ -57,54 +144,147 @@ pub fn do_publish(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtError
     let sendable = client::Sendable {
         exchange:     value_t!(matches, "exchange", String)?,
         routing_key:  value_t!(matches, "routing_key", String)?,
-        content_type: content_type,
+        content_type: content_types[0].clone(),
         headers:      values_t!(matches, "header", String).unwrap_or(vec![]),
         file_name:    file_name.to_owned(),
-        reader:       reader,
+        body:         body,
         priority:     value_t!(matches, "priority", u8).unwrap_or(0),
-        rpctimeout:   value_t!(matches, "rpctimeout", u64).unwrap_or(0)
+        rpctimeout:   value_t!(matches, "rpctimeout", u64).unwrap_or(0),
+        declare:      exchange_declare(matches),
+        direct_reply: matches.is_present("direct_reply"),
     };
 
-    // if we are doing an rpc with replyTo/correlationId, we set up a receiver
-    let rpc_receive = match rpc {
-        false => None,
-        true  => {
-            let receive =
-                move |channel: &mut Channel, deliver:Deliver, props:BasicProperties, body:Vec<u8>| ->
-                Result<(),RbtError> {
-                    let msg = output::build_output(false, &deliver, &props, body)?;
-
-                    // just write to stdout
-                    let stdout = io::stdout();
-
-                    // lock until end of scope
-                    let mut handle = stdout.lock();
-
-                    handle.write(&msg)?;
-                    handle.write(b"\n")?;
-                    handle.flush()?;
-           
-                    // closing the channel
-                    channel.close(200, "Bye")?;
-                    panic::set_hook(Box::new(|_| {
-                    }));
-                    // Until amqp library finds a way to exit consumer, terminate consumer_thread here.
-                    panic!(); 
-                    
-    
-                };
-
-            let receiver = client::Receiver {
-                exchange: "".to_owned(),
-                routing_key: None,
-                callback: Box::new(receive),
-                auto_ack: true,
-            };
-
-            Some(receiver)
+    // show what we would send, without connecting
+    if matches.is_present("dry_run") {
+        let mut props = client::build_props(&sendable)?;
+        let bodies:Vec<&[u8]> = match lines {
+            true  => requests.iter().map(|&(_, ref b)| &b[..]).collect(),
+            false => vec![&sendable.body[..]],
+        };
+        for (b, content_type) in bodies.into_iter().zip(content_types) {
+            props.content_type = Some(content_type);
+            let desc = output::describe_publish(&sendable.exchange, &sendable.routing_key,
+                                                rpc, &props, b);
+            write_stdout(&desc)?;
         }
+        return Ok(());
+    }
+
+    if lines {
+        let in_flight = match matches.value_of("in_flight") {
+            Some(n) => n.parse::<usize>().or(Err("--in-flight must be a number"))?,
+            None => 10,
+        };
+        if in_flight == 0 {
+            rbterr!("--in-flight must be at least 1");
+        }
+        let unordered = matches.is_present("unordered");
+        let requests = requests.into_iter().zip(content_types)
+            .map(|((n, body), content_type)| (n, content_type, body))
+            .collect();
+        return send_lines(opts, sendable, requests, in_flight, unordered);
+    }
+
+    if !rpc {
+        // ship it
+        return client::open_send(opts, sendable);
+    }
+
+    // rpc with replyTo/correlationId, the reply goes to stdout
+    let info    = matches.is_present("rpc_info");
+    let retries = match matches.value_of("retries") {
+        Some(n) => n.parse::<u32>().or(Err("--retries must be a number"))?,
+        None => 0,
+    };
+    let backoff = value_t!(matches, "retry_backoff", u64)?;
+
+    // without a timeout, we'd wait forever for the first reply
+    if retries > 0 && sendable.rpctimeout == 0 {
+        rbterr!("--retries needs a --rpctimeout to know when to retry");
+    }
+
+    let want = match matches.value_of("replies") {
+        None => {
+            return client::open_rpc(opts, sendable, Some(1), retries, backoff, |deliver, props, body| {
+                let msg = output::build_output(info, &deliver, &props, body)?;
+                write_stdout(&msg)
+            });
+        },
+        Some("all") => None,
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => rbterr!("--replies must be a number above 0, or all"),
+        },
     };
 
-    // ship it
-    client::open_send(opts, sendable, rpc_receive)
+    // scatter-gather, collect the replies into one array
+    if want.is_none() && sendable.rpctimeout == 0 {
+        rbterr!("--replies all needs a --rpctimeout to know when to stop");
+    }
+
+    let mut replies = vec![];
+    client::open_rpc(opts, sendable, want, retries, backoff, |deliver, props, body| {
+        replies.push((deliver, props, body));
+        Ok(())
+    })?;
+
+    let msg = output::build_gathered(info, replies)?;
+    write_stdout(&msg)
+}
+
+
+// split into non-empty lines, numbered from 1.
+fn split_lines(body:&[u8]) -> Vec<(usize, Vec<u8>)> {
+    body.split(|&b| b == b'\n')
+        .enumerate()
+        .map(|(i, l)| (i + 1, if l.ends_with(b"\r") { &l[..l.len() - 1] } else { l }))
+        .filter(|&(_, l)| !l.is_empty())
+        .map(|(i, l)| (i, l.to_vec()))
+        .collect()
+}
+
+
+// rpc each line, and output the replies in input order, or
+// as they arrive when unordered.
+fn send_lines(opts:amqp::Options, sendable:client::Sendable, requests:Vec<(usize, String, Vec<u8>)>,
+              in_flight:usize, unordered:bool) -> Result<(),RbtError> {
+
+    let line_nos:Vec<usize> = requests.iter().map(|&(n, _, _)| n).collect();
+    let bodies = requests.into_iter().map(|(_, c, b)| (c, b)).collect();
+
+    // replies waiting for an earlier line to be output
+    let mut done:BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+    let mut next = 0;
+
+    client::open_send_batch(opts, sendable, bodies, in_flight, |idx, correlation_id, reply| {
+        let reply = match reply {
+            client::BatchReply::Reply(props, body) => Ok((props, body)),
+            client::BatchReply::Timeout => Err("timeout".to_owned()),
+        };
+        let out = output::build_batch_line(line_nos[idx], correlation_id, reply);
+        if unordered {
+            return write_stdout(&out);
+        }
+        done.insert(idx, out);
+        while let Some(out) = done.remove(&next) {
+            write_stdout(&out)?;
+            next += 1;
+        }
+        Ok(())
+    })
+}
+
+
+fn write_stdout(msg:&[u8]) -> Result<(),RbtError> {
+
+    let stdout = io::stdout();
+
+    // lock until end of scope
+    let mut handle = stdout.lock();
+
+    handle.write_all(msg)?;
+    handle.write_all(b"\n")?;
+    handle.flush()?;
+
+    Ok(())
 }
@@ -0,0 +1,496 @@
+use rustc_serialize::json::{Json, Object};
+use regex::Regex;
+use std::cell::RefCell;
+use std::fs;
+use error::RbtError;
+
+
+// A JSON Schema (draft 4 to 7 keywords) that a body can be validated
+// against. Only local $ref ("#/definitions/foo") are followed.
+pub struct Schema {
+    root: Json,
+    // the $refs being followed, with the path they're followed at
+    resolving: RefCell<Vec<(String, String)>>,
+    // a $ref that led back to itself
+    cycle: RefCell<Option<String>>,
+}
+
+
+impl Schema {
+
+    pub fn from_file(file:&str) -> Result<Schema,RbtError> {
+        let mut reader = fs::File::open(file)?;
+        let root = Json::from_reader(&mut reader)
+            .map_err(|e| format!("Failed to read schema {}: {}", file, e))?;
+        Ok(Schema::new(root))
+    }
+
+    pub fn new(root:Json) -> Schema {
+        Schema {
+            root: root,
+            resolving: RefCell::new(vec![]),
+            cycle: RefCell::new(None),
+        }
+    }
+
+    // validate the doc and return a list of "path: problem" errors.
+    // an empty list means the doc is valid. a schema that refers to
+    // itself without getting anywhere in the doc is an error.
+    pub fn validate(&self, doc:&Json) -> Result<Vec<String>,String> {
+        let mut errs = vec![];
+        self.check(&self.root, doc, "$", &mut errs);
+        self.resolving.borrow_mut().clear();
+        if let Some(r) = self.cycle.borrow_mut().take() {
+            return Err(format!("$ref {} is a cycle", r));
+        }
+        Ok(errs)
+    }
+
+    fn check(&self, schema:&Json, doc:&Json, path:&str, errs:&mut Vec<String>) {
+
+        let obj = match *schema {
+            Json::Boolean(true) => return,
+            Json::Boolean(false) => {
+                errs.push(format!("{}: not allowed", path));
+                return;
+            },
+            Json::Object(ref o) => o,
+            _ => return,
+        };
+
+        // a $ref replaces all other keywords
+        if let Some(r) = obj.get("$ref").and_then(|r| r.as_string()) {
+            let s = match self.resolve(r) {
+                Some(s) => s,
+                None    => {
+                    errs.push(format!("{}: can't resolve $ref {}", path, r));
+                    return;
+                },
+            };
+            // the same $ref at the same place in the doc goes round forever
+            let key = (r.to_owned(), path.to_owned());
+            if self.cycle.borrow().is_some() || self.resolving.borrow().contains(&key) {
+                *self.cycle.borrow_mut() = Some(r.to_owned());
+                return;
+            }
+            self.resolving.borrow_mut().push(key);
+            self.check(s, doc, path, errs);
+            self.resolving.borrow_mut().pop();
+            return;
+        }
+
+        if let Some(t) = obj.get("type") {
+            let names:Vec<&str> = match *t {
+                Json::String(ref s) => vec![s.as_ref()],
+                Json::Array(ref a) => a.iter().filter_map(|v| v.as_string()).collect(),
+                _ => vec![],
+            };
+            if !names.is_empty() && !names.iter().any(|n| is_type(doc, n)) {
+                errs.push(format!("{}: expected {}, got {}", path, names.join(" or "), type_of(doc)));
+                return;
+            }
+        }
+
+        if let Some(&Json::Array(ref vals)) = obj.get("enum") {
+            if !vals.iter().any(|v| json_eq(v, doc)) {
+                let allowed:Vec<String> = vals.iter().map(|v| v.to_string()).collect();
+                errs.push(format!("{}: {} is not one of {}", path, doc, allowed.join(", ")));
+            }
+        }
+
+        if let Some(c) = obj.get("const") {
+            if !json_eq(c, doc) {
+                errs.push(format!("{}: expected {}, got {}", path, c, doc));
+            }
+        }
+
+        match *doc {
+            Json::I64(_) | Json::U64(_) | Json::F64(_) =>
+                check_number(obj, doc.as_f64().unwrap(), path, errs),
+            Json::String(ref s) => check_string(obj, s, path, errs),
+            Json::Array(ref a) => self.check_array(obj, a, path, errs),
+            Json::Object(ref o) => self.check_object(obj, o, path, errs),
+            _ => (),
+        }
+
+        if let Some(&Json::Array(ref subs)) = obj.get("allOf") {
+            for s in subs {
+                self.check(s, doc, path, errs);
+            }
+        }
+
+        if let Some(&Json::Array(ref subs)) = obj.get("anyOf") {
+            if !subs.iter().any(|s| self.is_valid(s, doc, path)) {
+                errs.push(format!("{}: does not match any schema in anyOf", path));
+            }
+        }
+
+        if let Some(&Json::Array(ref subs)) = obj.get("oneOf") {
+            let n = subs.iter().filter(|s| self.is_valid(s, doc, path)).count();
+            if n != 1 {
+                errs.push(format!("{}: matches {} schemas in oneOf, expected exactly 1", path, n));
+            }
+        }
+
+        if let Some(s) = obj.get("not") {
+            if self.is_valid(s, doc, path) {
+                errs.push(format!("{}: must not match the schema in not", path));
+            }
+        }
+    }
+
+    fn is_valid(&self, schema:&Json, doc:&Json, path:&str) -> bool {
+        let mut errs = vec![];
+        self.check(schema, doc, path, &mut errs);
+        errs.is_empty()
+    }
+
+    fn check_array(&self, obj:&Object, arr:&Vec<Json>,
+                   path:&str, errs:&mut Vec<String>) {
+
+        if let Some(n) = obj.get("minItems").and_then(|v| v.as_u64()) {
+            if (arr.len() as u64) < n {
+                errs.push(format!("{}: expected at least {} items, got {}", path, n, arr.len()));
+            }
+        }
+        if let Some(n) = obj.get("maxItems").and_then(|v| v.as_u64()) {
+            if (arr.len() as u64) > n {
+                errs.push(format!("{}: expected at most {} items, got {}", path, n, arr.len()));
+            }
+        }
+
+        if let Some(&Json::Boolean(true)) = obj.get("uniqueItems") {
+            for (i, a) in arr.iter().enumerate() {
+                if arr[..i].iter().any(|b| json_eq(a, b)) {
+                    errs.push(format!("{}[{}]: duplicate item", path, i));
+                }
+            }
+        }
+
+        match obj.get("items") {
+            // tuple validation
+            Some(&Json::Array(ref tuple)) => {
+                for (i, v) in arr.iter().enumerate() {
+                    let p = format!("{}[{}]", path, i);
+                    match tuple.get(i) {
+                        Some(s) => self.check(s, v, &p, errs),
+                        None => if let Some(s) = obj.get("additionalItems") {
+                            self.check(s, v, &p, errs);
+                        },
+                    }
+                }
+            },
+            Some(s) => {
+                for (i, v) in arr.iter().enumerate() {
+                    self.check(s, v, &format!("{}[{}]", path, i), errs);
+                }
+            },
+            None => (),
+        }
+
+        if let Some(s) = obj.get("contains") {
+            let any = arr.iter().enumerate()
+                .any(|(i, v)| self.is_valid(s, v, &format!("{}[{}]", path, i)));
+            if !any {
+                errs.push(format!("{}: no item matches the schema in contains", path));
+            }
+        }
+    }
+
+    fn check_object(&self, obj:&Object,
+                    doc:&Object,
+                    path:&str, errs:&mut Vec<String>) {
+
+        if let Some(&Json::Array(ref req)) = obj.get("required") {
+            for name in req.iter().filter_map(|v| v.as_string()) {
+                if !doc.contains_key(name) {
+                    errs.push(format!("{}: missing required property \"{}\"", path, name));
+                }
+            }
+        }
+
+        if let Some(n) = obj.get("minProperties").and_then(|v| v.as_u64()) {
+            if (doc.len() as u64) < n {
+                errs.push(format!("{}: expected at least {} properties, got {}", path, n, doc.len()));
+            }
+        }
+        if let Some(n) = obj.get("maxProperties").and_then(|v| v.as_u64()) {
+            if (doc.len() as u64) > n {
+                errs.push(format!("{}: expected at most {} properties, got {}", path, n, doc.len()));
+            }
+        }
+
+        let props = obj.get("properties").and_then(|p| p.as_object());
+        let patterns:Vec<(Regex, &Json)> = obj.get("patternProperties")
+            .and_then(|p| p.as_object())
+            .map(|p| p.iter().filter_map(|(k, s)| Regex::new(k).ok().map(|r| (r, s))).collect())
+            .unwrap_or(vec![]);
+
+        for (key, val) in doc {
+            let p = child_path(path, key);
+            let mut matched = false;
+
+            if let Some(s) = props.and_then(|p| p.get(key)) {
+                matched = true;
+                self.check(s, val, &p, errs);
+            }
+
+            for &(ref re, s) in &patterns {
+                if re.is_match(key) {
+                    matched = true;
+                    self.check(s, val, &p, errs);
+                }
+            }
+
+            if !matched {
+                match obj.get("additionalProperties") {
+                    Some(&Json::Boolean(false)) =>
+                        errs.push(format!("{}: property \"{}\" is not allowed", path, key)),
+                    Some(s) => self.check(s, val, &p, errs),
+                    None => (),
+                }
+            }
+        }
+    }
+
+    // resolve a local json pointer such as #/definitions/foo
+    fn resolve(&self, r:&str) -> Option<&Json> {
+        if !r.starts_with('#') {
+            return None;
+        }
+        let mut cur = &self.root;
+        for seg in r[1..].split('/').filter(|s| !s.is_empty()) {
+            let seg = seg.replace("~1", "/").replace("~0", "~");
+            cur = match *cur {
+                Json::Object(ref o) => o.get(&seg)?,
+                Json::Array(ref a) => a.get(seg.parse::<usize>().ok()?)?,
+                _ => return None,
+            };
+        }
+        Some(cur)
+    }
+}
+
+
+fn check_number(obj:&Object, n:f64, path:&str, errs:&mut Vec<String>) {
+
+    // draft 4 has exclusiveMinimum/Maximum as booleans modifying minimum/maximum
+    let excl_min = obj.get("exclusiveMinimum");
+    let excl_max = obj.get("exclusiveMaximum");
+
+    if let Some(min) = obj.get("minimum").and_then(|v| v.as_f64()) {
+        if let Some(&Json::Boolean(true)) = excl_min {
+            if n <= min {
+                errs.push(format!("{}: {} is not greater than {}", path, n, min));
+            }
+        } else if n < min {
+            errs.push(format!("{}: {} is less than the minimum {}", path, n, min));
+        }
+    }
+    if let Some(max) = obj.get("maximum").and_then(|v| v.as_f64()) {
+        if let Some(&Json::Boolean(true)) = excl_max {
+            if n >= max {
+                errs.push(format!("{}: {} is not less than {}", path, n, max));
+            }
+        } else if n > max {
+            errs.push(format!("{}: {} is greater than the maximum {}", path, n, max));
+        }
+    }
+    if let Some(min) = excl_min.and_then(|v| v.as_f64()) {
+        if n <= min {
+            errs.push(format!("{}: {} is not greater than {}", path, n, min));
+        }
+    }
+    if let Some(max) = excl_max.and_then(|v| v.as_f64()) {
+        if n >= max {
+            errs.push(format!("{}: {} is not less than {}", path, n, max));
+        }
+    }
+    if let Some(m) = obj.get("multipleOf").and_then(|v| v.as_f64()) {
+        if m > 0.0 && !is_multiple(n, m) {
+            errs.push(format!("{}: {} is not a multiple of {}", path, n, m));
+        }
+    }
+}
+
+
+// whether n is a multiple of m, allowing for the float error in
+// things like 0.3 / 0.1
+fn is_multiple(n:f64, m:f64) -> bool {
+    let q = n / m;
+    (q - q.round()).abs() <= 1e-9 * q.abs().max(1.0)
+}
+
+
+fn check_string(obj:&Object, s:&str, path:&str, errs:&mut Vec<String>) {
+
+    let len = s.chars().count() as u64;
+
+    if let Some(n) = obj.get("minLength").and_then(|v| v.as_u64()) {
+        if len < n {
+            errs.push(format!("{}: expected at least {} characters, got {}", path, n, len));
+        }
+    }
+    if let Some(n) = obj.get("maxLength").and_then(|v| v.as_u64()) {
+        if len > n {
+            errs.push(format!("{}: expected at most {} characters, got {}", path, n, len));
+        }
+    }
+    if let Some(pat) = obj.get("pattern").and_then(|v| v.as_string()) {
+        match Regex::new(pat) {
+            Ok(re) => if !re.is_match(s) {
+                errs.push(format!("{}: \"{}\" does not match pattern {}", path, s, pat));
+            },
+            Err(_) => errs.push(format!("{}: invalid pattern in schema {}", path, pat)),
+        }
+    }
+}
+
+
+fn is_type(doc:&Json, name:&str) -> bool {
+    match (name, doc) {
+        ("null", &Json::Null) => true,
+        ("boolean", &Json::Boolean(_)) => true,
+        ("string", &Json::String(_)) => true,
+        ("array", &Json::Array(_)) => true,
+        ("object", &Json::Object(_)) => true,
+        ("number", d) => d.is_number(),
+        ("integer", &Json::I64(_)) | ("integer", &Json::U64(_)) => true,
+        ("integer", &Json::F64(f)) => f.fract() == 0.0,
+        _ => false,
+    }
+}
+
+
+fn type_of(doc:&Json) -> &'static str {
+    match *doc {
+        Json::Null => "null",
+        Json::Boolean(_) => "boolean",
+        Json::String(_) => "string",
+        Json::Array(_) => "array",
+        Json::Object(_) => "object",
+        Json::I64(_) | Json::U64(_) => "integer",
+        Json::F64(_) => "number",
+    }
+}
+
+
+// equality where 1, 1u and 1.0 are the same number
+fn json_eq(a:&Json, b:&Json) -> bool {
+    match (a, b) {
+        (&Json::Array(ref x), &Json::Array(ref y)) =>
+            x.len() == y.len() && x.iter().zip(y.iter()).all(|(a, b)| json_eq(a, b)),
+        (&Json::Object(ref x), &Json::Object(ref y)) =>
+            x.len() == y.len() && x.iter().all(|(k, v)| y.get(k).map(|w| json_eq(v, w)).unwrap_or(false)),
+        _ if a.is_number() && b.is_number() => a.as_f64() == b.as_f64(),
+        _ => a == b,
+    }
+}
+
+
+fn child_path(path:&str, key:&str) -> String {
+    let plain = !key.is_empty() &&
+        key.chars().all(|c| c.is_alphanumeric() || c == '_') &&
+        !key.chars().next().unwrap().is_numeric();
+    if plain {
+        format!("{}.{}", path, key)
+    } else {
+        format!("{}[{}]", path, Json::String(key.to_owned()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(schema:&str, doc:&str) -> Vec<String> {
+        let schema = Schema::new(Json::from_str(schema).unwrap());
+        schema.validate(&Json::from_str(doc).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn required() {
+        let s = r##"{"type":"object","required":["id","name"]}"##;
+        assert!(errors(s, r##"{"id":1,"name":"a"}"##).is_empty());
+        assert_eq!(errors(s, r##"{"id":1}"##), vec!["$: missing required property \"name\""]);
+    }
+
+    #[test]
+    fn additional_properties() {
+        let s = r##"{"properties":{"id":{"type":"integer"}},"additionalProperties":false}"##;
+        assert!(errors(s, r##"{"id":1}"##).is_empty());
+        assert_eq!(errors(s, r##"{"id":1,"x":2}"##), vec!["$: property \"x\" is not allowed"]);
+
+        let s = r##"{"properties":{"id":{}},"additionalProperties":{"type":"string"}}"##;
+        assert!(errors(s, r##"{"id":1,"x":"a"}"##).is_empty());
+        assert_eq!(errors(s, r##"{"x":2}"##), vec!["$.x: expected string, got integer"]);
+    }
+
+    #[test]
+    fn items() {
+        let s = r##"{"properties":{"tags":{"items":{"type":"string"}}}}"##;
+        assert!(errors(s, r##"{"tags":["a","b"]}"##).is_empty());
+        assert_eq!(errors(s, r##"{"tags":[1,"b"]}"##), vec!["$.tags[0]: expected string, got integer"]);
+
+        // tuples
+        let s = r##"{"items":[{"type":"integer"},{"type":"string"}],"additionalItems":false}"##;
+        assert!(errors(s, r##"[1,"a"]"##).is_empty());
+        assert_eq!(errors(s, r##"[1,"a",true]"##), vec!["$[2]: not allowed"]);
+    }
+
+    #[test]
+    fn refs() {
+        let s = r##"{"definitions":{"id":{"type":"integer","minimum":1}},
+                    "properties":{"id":{"$ref":"#/definitions/id"}}}"##;
+        assert!(errors(s, r##"{"id":3}"##).is_empty());
+        assert_eq!(errors(s, r##"{"id":0}"##), vec!["$.id: 0 is less than the minimum 1"]);
+
+        let s = r##"{"properties":{"id":{"$ref":"#/definitions/nope"}}}"##;
+        assert_eq!(errors(s, r##"{"id":3}"##), vec!["$.id: can't resolve $ref #/definitions/nope"]);
+    }
+
+    #[test]
+    fn recursive_refs() {
+        // a tree is fine, it gets somewhere in the doc each time
+        let s = r##"{"type":"object","properties":{"kids":{"items":{"$ref":"#"}}}}"##;
+        assert!(errors(s, r##"{"kids":[{"kids":[{"kids":[]}]}]}"##).is_empty());
+        assert_eq!(errors(s, r##"{"kids":[{"kids":[1]}]}"##),
+                   vec!["$.kids[0].kids[0]: expected object, got integer"]);
+    }
+
+    #[test]
+    fn ref_cycles() {
+        let cycle = |s:&str| Schema::new(Json::from_str(s).unwrap())
+            .validate(&Json::from_str("{}").unwrap()).unwrap_err();
+        assert_eq!(cycle(r##"{"$ref":"#"}"##), "$ref # is a cycle");
+        assert_eq!(cycle(r##"{"definitions":{"a":{"$ref":"#/definitions/b"},
+                                             "b":{"allOf":[{"$ref":"#/definitions/a"}]}},
+                          "$ref":"#/definitions/a"}"##),
+                   "$ref #/definitions/a is a cycle");
+    }
+
+    #[test]
+    fn multiple_of() {
+        let s = r##"{"multipleOf":0.1}"##;
+        assert!(errors(s, "0.3").is_empty());
+        assert!(errors(s, "12.7").is_empty());
+        assert_eq!(errors(s, "0.35").len(), 1);
+
+        let s = r##"{"multipleOf":3}"##;
+        assert!(errors(s, "9").is_empty());
+        assert!(errors(s, "-9").is_empty());
+        assert_eq!(errors(s, "10"), vec!["$: 10 is not a multiple of 3"]);
+    }
+
+    #[test]
+    fn error_paths() {
+        assert_eq!(child_path("$", "id"), "$.id");
+        assert_eq!(child_path("$", "x y"), "$[\"x y\"]");
+        assert_eq!(child_path("$", "1st"), "$[\"1st\"]");
+        assert_eq!(child_path("$", ""), "$[\"\"]");
+
+        let s = r##"{"properties":{"x y":{"type":"string"}}}"##;
+        assert_eq!(errors(s, r##"{"x y":1}"##), vec!["$[\"x y\"]: expected string, got integer"]);
+    }
+}
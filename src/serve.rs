@@ -0,0 +1,176 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use amqp::protocol::basic::{Deliver, BasicProperties};
+use amqp::{self, Basic, Channel, Table, TableEntry};
+use clap::ArgMatches;
+use rustc_serialize::json::Json;
+use client;
+use error::RbtError;
+use output;
+use sniff;
+
+
+pub fn do_serve_rpc(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtError> {
+
+    let cmd = value_t!(matches, "exec", String)?;
+    let content_type = matches.value_of("content_type").map(str::to_owned);
+    let nack = matches.value_of("on_fail") == Some("nack");
+    let queue : Option<String> = matches.value_of("queue").map(str::to_owned);
+    let declare = match matches.is_present("declare") {
+        true  => Some(client::QueueDeclare::default()),
+        false => None,
+    };
+    let exchange = matches.value_of("exchange").unwrap_or("").to_owned();
+
+    if queue.is_none() && exchange == "" {
+        rbterr!("Need a queue (-q) or an exchange (-e) to serve");
+    }
+
+    let receive = move |channel: &mut Channel, deliver:Deliver, props:BasicProperties, body:Vec<u8>| ->
+        Result<(),RbtError> {
+
+        let delivery_tag = deliver.delivery_tag;
+
+        // nowhere to send a reply
+        let reply_to = match props.reply_to.clone() {
+            Some(r) => r,
+            None => {
+                errln!("Request {} has no reply_to, dropping it", delivery_tag);
+                channel.basic_ack(delivery_tag, false)?;
+                return Ok(());
+            }
+        };
+
+        let out = run(&cmd, &deliver, &props, body)?;
+        let code = out.status.code().unwrap_or(-1);
+
+        if !out.status.success() {
+            errln!("Request {}: {} exited with {}", delivery_tag, cmd, code);
+            if nack {
+                channel.basic_nack(delivery_tag, false, false)?;
+                return Ok(());
+            }
+        }
+
+        // the reply is the output of the command, or an error
+        // reply with its stderr and exit code.
+        let mut headers = Table::new();
+        let (reply_type, reply_body) = if out.status.success() {
+            let t = content_type.clone().unwrap_or_else(|| sniff::sniff(&out.stdout).to_owned());
+            (t, out.stdout)
+        } else {
+            headers.insert("exitCode".to_owned(), TableEntry::LongInt(code));
+            ("text/plain".to_owned(), out.stderr)
+        };
+
+        let reply_props = BasicProperties {
+            content_type: Some(reply_type),
+            correlation_id: props.correlation_id.clone(),
+            headers: Some(headers),
+            ..Default::default()
+        };
+
+        channel.basic_publish("".to_owned(), reply_to, false, false, reply_props, reply_body)?;
+        channel.basic_ack(delivery_tag, false)?;
+
+        Ok(())
+    };
+
+    let receiver = client::Receiver {
+        bindings: vec![client::Binding {
+            exchange: exchange,
+            routing_key: matches.value_of("routing_key").map(str::to_owned),
+            arguments: Table::new(),
+        }],
+        auto_ack: false, // after the reply is sent
+        on_error: client::OnError::Exit,
+        failures: client::Failures::default(),
+        prefetch: None,
+        arguments: Table::new(),
+        correlation_id: None,
+        filter: None,
+        callback: Box::new(receive),
+    };
+
+    client::open_receive(opts, queue, declare, client::Limits::default(), receiver)
+}
+
+
+// run the command with the body on stdin and the message
+// properties and headers in the environment.
+fn run(cmd:&str, deliver:&Deliver, props:&BasicProperties, body:Vec<u8>) -> Result<Output,RbtError> {
+
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    for (k, v) in message_env(deliver, props) {
+        command.env(k, v);
+    }
+
+    let mut child = command.spawn()
+        .map_err(|e| format!("Failed to run {}: {}", cmd, e))?;
+
+    // write in a thread of its own, or a command that outputs
+    // a lot before reading all input would block us both.
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || {
+        // the command is free to not read its input
+        let _ = stdin.write_all(&body);
+    });
+
+    let out = child.wait_with_output()?;
+    writer.join().or(Err("Failed to write request to command"))?;
+
+    Ok(out)
+}
+
+
+// AMQP_ROUTING_KEY=..., AMQP_CORRELATION_ID=..., AMQP_HEADER_MY_HEADER=...
+fn message_env(deliver:&Deliver, props:&BasicProperties) -> Vec<(String, String)> {
+
+    let mut env = vec![
+        ("AMQP_EXCHANGE".to_owned(), deliver.exchange.clone()),
+        ("AMQP_ROUTING_KEY".to_owned(), deliver.routing_key.clone()),
+        ("AMQP_DELIVERY_TAG".to_owned(), deliver.delivery_tag.to_string()),
+        ("AMQP_REDELIVERED".to_owned(), deliver.redelivered.to_string()),
+    ];
+
+    {
+        let mut put = |name:&str, value:Option<String>| {
+            if let Some(v) = value {
+                env.push((format!("AMQP_{}", name), v));
+            }
+        };
+        put("CONTENT_TYPE", props.content_type.clone());
+        put("CONTENT_ENCODING", props.content_encoding.clone());
+        put("DELIVERY_MODE", props.delivery_mode.map(|v| v.to_string()));
+        put("PRIORITY", props.priority.map(|v| v.to_string()));
+        put("CORRELATION_ID", props.correlation_id.clone());
+        put("REPLY_TO", props.reply_to.clone());
+        put("EXPIRATION", props.expiration.clone());
+        put("MESSAGE_ID", props.message_id.clone());
+        put("TIMESTAMP", props.timestamp.map(|v| v.to_string()));
+        put("TYPE", props._type.clone());
+        put("USER_ID", props.user_id.clone());
+        put("APP_ID", props.app_id.clone());
+    }
+
+    if let Some(ref headers) = props.headers {
+        for (k, v) in output::table_to_json(headers) {
+            let name:String = k.chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+                .collect();
+            let value = match v {
+                Json::String(s) => s,
+                other => other.to_string(),
+            };
+            env.push((format!("AMQP_HEADER_{}", name), value));
+        }
+    }
+
+    env
+}
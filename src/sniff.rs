@@ -0,0 +1,115 @@
+use rustc_serialize::json::Json;
+use std::str;
+
+
+// magic numbers at the start of the body and the type they indicate
+static MAGIC:&'static [(&'static [u8], &'static str)] = &[
+    (b"\x89PNG\r\n\x1a\n",         "image/png"),
+    (b"\xff\xd8\xff",              "image/jpeg"),
+    (b"GIF87a",                    "image/gif"),
+    (b"GIF89a",                    "image/gif"),
+    (b"II*\x00",                   "image/tiff"),
+    (b"MM\x00*",                   "image/tiff"),
+    (b"\x00\x00\x01\x00",          "image/x-icon"),
+    (b"%PDF-",                     "application/pdf"),
+    (b"PK\x03\x04",                "application/zip"),
+    (b"PK\x05\x06",                "application/zip"),
+    (b"\x1f\x8b",                  "application/gzip"),
+    (b"BZh",                       "application/x-bzip2"),
+    (b"\xfd7zXZ\x00",              "application/x-xz"),
+    (b"7z\xbc\xaf\x27\x1c",        "application/x-7z-compressed"),
+    (b"Rar!\x1a\x07",              "application/vnd.rar"),
+];
+
+static OCTET_STREAM:&'static str = "application/octet-stream";
+
+
+// sniff the content type of a body by looking at the bytes. falls
+// back on application/octet-stream when nothing matches.
+pub fn sniff(body:&[u8]) -> &'static str {
+
+    if body.is_empty() {
+        return OCTET_STREAM;
+    }
+
+    for &(magic, mime) in MAGIC {
+        if body.starts_with(magic) {
+            return mime;
+        }
+    }
+
+    // RIFF container with WEBP payload
+    if body.len() >= 12 && &body[0..4] == b"RIFF" && &body[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+
+    // posix tar has its magic at offset 257
+    if body.len() >= 262 && &body[257..262] == b"ustar" {
+        return "application/x-tar";
+    }
+
+    // anything else must be text
+    let text = match str::from_utf8(body) {
+        Ok(t) => t,
+        Err(_) => return OCTET_STREAM,
+    };
+
+    if text.chars().any(is_binary_char) {
+        return OCTET_STREAM;
+    }
+
+    let trimmed = text.trim_start_matches('\u{feff}').trim();
+
+    // "[INFO] started" is text, not broken JSON
+    if (trimmed.starts_with('{') || trimmed.starts_with('[')) && Json::from_str(trimmed).is_ok() {
+        return "application/json";
+    }
+
+    if looks_like_xml(trimmed) {
+        return "application/xml";
+    }
+
+    "text/plain"
+}
+
+
+// control characters that never appear in a text file
+fn is_binary_char(c:char) -> bool {
+    c.is_control() && c != '\n' && c != '\r' && c != '\t' && c != '\x0c'
+}
+
+
+// an xml declaration, or a root element that is closed at the end.
+fn looks_like_xml(text:&str) -> bool {
+    if text.starts_with("<?xml") {
+        return true;
+    }
+    let mut chars = text.chars();
+    let starts = chars.next() == Some('<') &&
+        chars.next().map(|c| c.is_alphabetic() || c == '!').unwrap_or(false);
+    starts && text.ends_with('>')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_only_when_it_parses() {
+        assert_eq!(sniff(b" {\"a\":1}\n"), "application/json");
+        assert_eq!(sniff(b"[1,2]"), "application/json");
+        assert_eq!(sniff(b"[INFO] server started\n"), "text/plain");
+        assert_eq!(sniff(b"{\"a\":"), "text/plain");
+    }
+
+    #[test]
+    fn other_types() {
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?><a/>"), "application/xml");
+        assert_eq!(sniff(b"<a>x</a>"), "application/xml");
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n...."), "image/png");
+        assert_eq!(sniff(b"\x00\x01\x02x"), "application/octet-stream");
+        assert_eq!(sniff(b""), "application/octet-stream");
+        assert_eq!(sniff(b"hello"), "text/plain");
+    }
+}
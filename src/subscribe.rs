@@ -1,24 +1,112 @@
 use std::io::{self, Write};
 use rand::{thread_rng, Rng, distributions::Alphanumeric};
 use amqp::protocol::basic::{Deliver, BasicProperties};
-use amqp::{self, TableEntry, Channel};
+use amqp::{self, Table, TableEntry, Channel};
 use clap::ArgMatches;
 use std::fs;
 use std::path::Path;
+use std::time::Duration;
 use mime;
 use client;
+use filter::Filter;
+use iso8601;
 use output;
+use template;
 use error::RbtError;
-use std::panic;
 
 
 pub fn do_subscribe(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtError> {
 
     let output = value_t!(matches, "output", String)?;
     let queue : Option<String> = matches.value_of("queue").map(str::to_owned);
-    let force_declare : bool = matches.is_present("declare");
-    let info   = matches.is_present("info");
-    let single = matches.is_present("single");
+    let declare = queue_declare_of(matches)?;
+    let format = format_of(matches)?;
+    let ack    = ! matches.is_present("noack");
+
+    let dead_letter_to = matches.value_of("dead_letter_to").map(str::to_owned);
+    let on_error = match matches.value_of("on_error") {
+        Some("skip")        => client::OnError::Skip,
+        Some("nack")        => client::OnError::Nack,
+        Some("requeue")     => client::OnError::Requeue,
+        Some("dead-letter") => client::OnError::DeadLetter(dead_letter_to),
+        _                   => client::OnError::Exit,
+    };
+    let failures = client::Failures::default();
+
+    // where to start in a stream queue
+    // a checkpoint from last time wins over --offset
+    let checkpoint = matches.value_of("checkpoint").map(str::to_owned);
+    let resume = match checkpoint {
+        Some(ref file) => read_checkpoint(file)?,
+        None => None,
+    };
+
+    let mut arguments = Table::new();
+    if let Some(last) = resume {
+        arguments.insert("x-stream-offset".to_owned(), TableEntry::LongLongInt(last + 1));
+    } else if let Some(offset) = matches.value_of("offset") {
+        arguments.insert("x-stream-offset".to_owned(), stream_offset(offset)?);
+    } else if checkpoint.is_some() {
+        arguments.insert("x-stream-offset".to_owned(), TableEntry::LongString("next".to_owned()));
+    }
+
+    let prefetch = match matches.value_of("prefetch") {
+        Some(n) => Some((n.parse::<u16>().or(Err("--prefetch must be a number 0-65535"))?,
+                         matches.is_present("prefetch_global"))),
+        // streams can't be consumed without one
+        None if !arguments.is_empty() => Some((STREAM_PREFETCH, false)),
+        None => None,
+    };
+
+    // --single is --count 1
+    let limits = client::Limits {
+        count: match matches.is_present("single") {
+            true  => Some(1),
+            false => match matches.value_of("count") {
+                Some(n) => Some(n.parse::<u64>().or(Err("--count must be a number"))?),
+                None => None,
+            },
+        },
+        idle_timeout: match matches.value_of("idle_timeout") {
+            Some(d) => Some(parse_duration(d)?),
+            None => None,
+        },
+        drain: matches.is_present("drain"),
+    };
+
+    if limits.drain && queue.is_none() {
+        rbterr!("--drain needs a queue (-q)");
+    }
+
+    // every --filter must match
+    let mut filters = vec![];
+    for expr in values_t!(matches, "filter", String).unwrap_or(vec![]) {
+        filters.push(Filter::parse(&expr)?);
+    }
+    // skipped messages are done with too, or a restart reads them again
+    let skipped = checkpoint.clone();
+    let filter:Option<Box<client::FilterCb>> = match filters.is_empty() {
+        true  => None,
+        false => Some(Box::new(move |deliver:&Deliver, props:&BasicProperties, body:&[u8]| {
+            let msg = output::message_json(deliver, props, body);
+            let keep = filters.iter().all(|f| f.matches(&msg));
+            if let (false, Some(file), Some(offset)) = (keep, skipped.as_ref(), output::stream_offset(props)) {
+                write_checkpoint(file, offset)
+                    .unwrap_or_else(|e| errln!("Failed to write checkpoint {}: {}", file, e));
+            }
+            keep
+        })),
+    };
+
+    let bindings = bindings_of(matches)?;
+
+    // tell interleaved streams apart, unless the format has the
+    // exchange and routing key already
+    let tagged = bindings.len() > 1;
+    let bare = match format {
+        output::Format::Body | output::Format::Raw | output::Format::Hexdump => true,
+        _ => false,
+    };
 
     // type lookup map
     let types = mime::Types::new().or(Err("Failed to read mime types"))?;
@@ -33,10 +121,10 @@ pub fn do_subscribe(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtErr
         }
     }
 
-    let receive = move |channel: &mut Channel, deliver:Deliver, props:BasicProperties, body:Vec<u8>| ->
+    let receive = move |_: &mut Channel, deliver:Deliver, props:BasicProperties, body:Vec<u8>| ->
         Result<(),RbtError> {
 
-        let msg = output::build_output(info, &deliver, &props, body)?;
+        let msg = output::build_formatted(&format, &deliver, &props, body)?;
 
         match output.as_ref() {
             "-" => {
@@ -47,8 +135,12 @@ pub fn do_subscribe(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtErr
                 // lock until end of scope
                 let mut handle = stdout.lock();
 
-                handle.write(&msg)?;
-                handle.write(b"\n")?;
+                if tagged && bare {
+                    write!(handle, "==> {} {} <==\n", deliver.exchange, deliver.routing_key)?;
+                }
+
+                handle.write_all(&msg)?;
+                handle.write_all(b"\n")?;
                 handle.flush()?;
 
             },
@@ -66,7 +158,11 @@ pub fn do_subscribe(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtErr
                     rbterr!("Output {} is not a directory", output);
                 }
 
-                errln!("{}", path.to_str().unwrap());
+                if tagged {
+                    errln!("{} ({} {})", path.to_str().unwrap(), deliver.exchange, deliver.routing_key);
+                } else {
+                    errln!("{}", path.to_str().unwrap());
+                }
 
                 let mut f = fs::File::create(path)?;
                 f.write_all(&msg)?;
@@ -75,14 +171,11 @@ pub fn do_subscribe(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtErr
 
         }
 
-        // maybe end here?
-        if single {
-            // closing the channel
-            channel.close(200, "Bye")?;
-            panic::set_hook(Box::new(|_| {
-            }));
-            // Until amqp library finds a way to exit consumer, terminate consumer_thread here.
-            panic!();
+        // done with it, next time we start after this one
+        if let Some(ref file) = checkpoint {
+            if let Some(offset) = output::stream_offset(&props) {
+                write_checkpoint(file, offset)?;
+            }
         }
 
         Ok(())
@@ -90,13 +183,194 @@ pub fn do_subscribe(opts:amqp::Options, matches:&ArgMatches) -> Result<(),RbtErr
     };
 
     let receiver = client::Receiver {
-        exchange: value_t!(matches, "exchange", String)?,
-        routing_key: matches.value_of("routing_key").map(str::to_owned),
-        auto_ack: ! matches.is_present("noack"),
+        bindings: bindings,
+        auto_ack: ack,
+        on_error: on_error,
+        failures: failures.clone(),
+        prefetch: prefetch,
+        arguments: arguments,
+        correlation_id: None,
+        filter: filter,
         callback: Box::new(receive),
     };
 
-    client::open_receive(opts, queue, force_declare, receiver)
+    let res = client::open_receive(opts, queue, declare, limits, receiver);
+    client::print_failures(&failures);
+    res
+}
+
+// --format, or -i for the envelope, or --template
+fn format_of(matches:&ArgMatches) -> Result<output::Format,RbtError> {
+    // --template alone is enough
+    let format = matches.value_of("format")
+        .or(if matches.is_present("template") { Some("template") } else { None });
+    Ok(match format {
+        Some("pretty")   => output::Format::Pretty,
+        Some("jsonl")    => output::Format::Jsonl,
+        Some("hexdump")  => output::Format::Hexdump,
+        Some("template") => {
+            let tpl = value_t!(matches, "template", String)?;
+            template::check(&tpl)?;
+            output::Format::Template(tpl)
+        },
+        Some(_) => output::Format::Raw,
+        None if matches.is_present("info") => output::Format::Pretty,
+        None => output::Format::Body,
+    })
+}
+
+// prefetch for stream queues, unless --prefetch is given
+const STREAM_PREFETCH:u16 = 100;
+
+// first, last, next, an offset or a time
+fn stream_offset(offset:&str) -> Result<TableEntry,RbtError> {
+    match offset {
+        "first" | "last" | "next" => Ok(TableEntry::LongString(offset.to_owned())),
+        _ => {
+            if let Ok(n) = offset.parse::<i64>() {
+                Ok(TableEntry::LongLongInt(n))
+            } else if let Some(t) = iso8601::parse(offset) {
+                Ok(TableEntry::Timestamp(t))
+            } else {
+                rbterr!("Bad --offset {}, use first, last, next, a number or a time such as 2024-05-01T12:00:00Z",
+                        offset);
+            }
+        }
+    }
+}
+
+// the last processed offset, if there is a checkpoint file
+fn read_checkpoint(file:&str) -> Result<Option<i64>,RbtError> {
+    let text = match fs::read_to_string(file) {
+        Ok(t) => t,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match text.trim().parse::<i64>() {
+        Ok(n) => Ok(Some(n)),
+        Err(_) => rbterr!("Checkpoint {} doesn't hold an offset", file),
+    }
+}
+
+// write to a temp file and rename it in place, so a crash leaves
+// either the old or the new checkpoint, never half of one.
+fn write_checkpoint(file:&str, offset:i64) -> Result<(),RbtError> {
+    let tmp = format!("{}.tmp", file);
+    {
+        let mut f = fs::File::create(&tmp)?;
+        write!(f, "{}\n", offset)?;
+        f.sync_all()?;
+    }
+    fs::rename(&tmp, file)?;
+    Ok(())
+}
+
+// the queue declaration from -d and the queue options, which
+// imply -d. None when the queue is assumed to exist.
+fn queue_declare_of(matches:&ArgMatches) -> Result<Option<client::QueueDeclare>,RbtError> {
+
+    // the anonymous queue is always exclusive and auto-delete,
+    // which quorum and stream queues can't be
+    if !matches.is_present("queue") {
+        if let Some(t) = matches.value_of("queue_type") {
+            if t != "classic" {
+                rbterr!("--queue-type {} needs a named queue (-q), the anonymous queue is exclusive", t);
+            }
+        }
+        for flag in &["exclusive", "auto_delete"] {
+            if matches.is_present(flag) {
+                rbterr!("--{} needs a named queue (-q), the anonymous queue is always exclusive and auto-delete",
+                        flag.replace('_', "-"));
+            }
+        }
+    }
+
+    let mut arguments = Table::new();
+
+    if let Some(t) = matches.value_of("queue_type") {
+        arguments.insert("x-queue-type".to_owned(), TableEntry::LongString(t.to_owned()));
+    }
+    if let Some(ttl) = matches.value_of("message_ttl") {
+        let ttl = ttl.parse::<i64>().or(Err("--message-ttl must be milliseconds"))?;
+        arguments.insert("x-message-ttl".to_owned(), TableEntry::LongLongInt(ttl));
+    }
+    if let Some(n) = matches.value_of("max_length") {
+        let n = n.parse::<i64>().or(Err("--max-length must be a number"))?;
+        arguments.insert("x-max-length".to_owned(), TableEntry::LongLongInt(n));
+    }
+    if let Some(x) = matches.value_of("dead_letter_exchange") {
+        arguments.insert("x-dead-letter-exchange".to_owned(), TableEntry::LongString(x.to_owned()));
+    }
+    if matches.is_present("single_active_consumer") {
+        arguments.insert("x-single-active-consumer".to_owned(), TableEntry::Bool(true));
+    }
+    for st in values_t!(matches, "queue_arg", String).unwrap_or(vec![]) {
+        let idx = st.find('=').ok_or("Queue argument must be key=value")?;
+        let (key, value) = st.split_at(idx);
+        arguments.insert(key.trim().to_owned(), client::narrow_int(value[1..].trim()));
+    }
+
+    let durable     = matches.is_present("durable");
+    let exclusive   = matches.is_present("exclusive");
+    let auto_delete = matches.is_present("auto_delete");
+
+    if !matches.is_present("declare") && !durable && !exclusive && !auto_delete && arguments.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(client::QueueDeclare {
+        durable: durable,
+        exclusive: exclusive,
+        auto_delete: auto_delete,
+        arguments: arguments,
+    }))
+}
+
+// every -e with every -r, and the -b exchange:key pairs
+fn bindings_of(matches:&ArgMatches) -> Result<Vec<client::Binding>,RbtError> {
+
+    // the same arguments for every binding
+    let mut arguments = Table::new();
+    for st in values_t!(matches, "bind_arg", String).unwrap_or(vec![]) {
+        let (key, val) = client::parse_entry(&st, '=').ok_or("Binding argument must be key=value")?;
+        arguments.insert(key, val);
+    }
+    for st in values_t!(matches, "bind_header", String).unwrap_or(vec![]) {
+        let (key, val) = client::parse_entry(&st, ':').ok_or("Binding header must have a :")?;
+        arguments.insert(key, val);
+    }
+
+    let exchanges = values_t!(matches, "exchange", String).unwrap_or(vec![]);
+    let routing_keys:Vec<Option<String>> = match values_t!(matches, "routing_key", String) {
+        Ok(keys) => keys.into_iter().map(Some).collect(),
+        Err(_)   => vec![None],
+    };
+
+    let mut bindings = vec![];
+    for exchange in &exchanges {
+        for routing_key in &routing_keys {
+            bindings.push(client::Binding {
+                exchange: exchange.clone(),
+                routing_key: routing_key.clone(),
+                arguments: arguments.clone(),
+            });
+        }
+    }
+
+    for pair in values_t!(matches, "bind", String).unwrap_or(vec![]) {
+        let mut parts = pair.splitn(2, ':');
+        let exchange = parts.next().unwrap_or("");
+        if exchange == "" {
+            rbterr!("Binding needs to be exchange:key, got {}", pair);
+        }
+        bindings.push(client::Binding {
+            exchange: exchange.to_owned(),
+            routing_key: parts.next().map(str::to_owned),
+            arguments: arguments.clone(),
+        });
+    }
+
+    Ok(bindings)
 }
 
 fn file_name_of(props:&BasicProperties, types:&mime::Types) -> String {
@@ -128,3 +402,131 @@ fn gen_rand_name(ext:String) -> String {
     rand.push_str(&ext);
     rand
 }
+
+// 500ms, 5s, 2m or 1h. a bare number is seconds.
+fn parse_duration(s:&str) -> Result<Duration,RbtError> {
+    let s = s.trim();
+    let split = s.find(|c:char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n:u64 = match n.parse() {
+        Ok(n) => n,
+        Err(_) => rbterr!("Bad duration: {}", s),
+    };
+    let unit_millis = match unit.trim() {
+        "ms"     => 1,
+        "s" | "" => 1000,
+        "m"      => 60 * 1000,
+        "h"      => 60 * 60 * 1000,
+        _        => rbterr!("Bad duration: {}", s),
+    };
+    match n.checked_mul(unit_millis) {
+        Some(millis) => Ok(Duration::from_millis(millis)),
+        None => rbterr!("Bad duration: {}", s),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+
+    fn matches(args:&[&str]) -> ArgMatches<'static> {
+        let multi = |name:&'static str, long:&'static str| Arg::with_name(name)
+            .long(long).takes_value(true).multiple(true).number_of_values(1);
+        App::new("subscribe")
+            .arg(multi("exchange", "exchange").short("e"))
+            .arg(multi("routing_key", "routing-key").short("r"))
+            .arg(multi("bind", "bind").short("b"))
+            .arg(multi("bind_arg", "bind-arg"))
+            .arg(multi("bind_header", "bind-header"))
+            .get_matches_from(::std::iter::once(&"subscribe").chain(args.iter()))
+    }
+
+    fn bindings(args:&[&str]) -> Vec<client::Binding> {
+        bindings_of(&matches(args)).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[test]
+    fn bindings_cross_exchanges_and_keys() {
+        let b = bindings(&["-e", "a", "-e", "b", "-r", "x", "-r", "y", "-b", "c:z"]);
+        let pairs:Vec<(&str, Option<&str>)> = b.iter()
+            .map(|b| (b.exchange.as_ref(), b.routing_key.as_ref().map(|k| k.as_ref())))
+            .collect();
+        assert_eq!(pairs, vec![("a", Some("x")), ("a", Some("y")), ("b", Some("x")),
+                               ("b", Some("y")), ("c", Some("z"))]);
+    }
+
+    #[test]
+    fn bindings_without_key() {
+        let b = bindings(&["-e", "a"]);
+        assert_eq!(b.len(), 1);
+        assert_eq!(b[0].routing_key, None);
+        // a named queue isn't bound, an anonymous one is with #
+        assert_eq!(b[0].bind_key(None), None);
+        assert_eq!(b[0].bind_key(Some(&"#".to_owned())), Some("#".to_owned()));
+    }
+
+    #[test]
+    fn headers_bindings_without_key() {
+        let b = bindings(&["-e", "hx", "--bind-arg", "x-match=all", "--bind-header", "region: eu"]);
+        assert_eq!(b.len(), 1);
+        assert_eq!(b[0].arguments.len(), 2);
+        // bound with an empty key, named queue or not
+        assert_eq!(b[0].bind_key(None), Some("".to_owned()));
+        assert_eq!(b[0].bind_key(Some(&"#".to_owned())), Some("".to_owned()));
+    }
+
+    #[test]
+    fn bad_bindings() {
+        assert!(bindings_of(&matches(&["-b", ":key"])).is_err());
+        assert!(bindings_of(&matches(&["-e", "a", "--bind-arg", "x-match"])).is_err());
+        assert!(bindings_of(&matches(&["-e", "a", "--bind-header", "region"])).is_err());
+    }
+
+    fn millis(s:&str) -> u64 {
+        let d = parse_duration(s).unwrap_or_else(|e| panic!("{}", e));
+        d.as_secs() * 1000 + d.subsec_millis() as u64
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(millis("250ms"), 250);
+        assert_eq!(millis("30s"), 30_000);
+        assert_eq!(millis("30"), 30_000);
+        assert_eq!(millis("5m"), 300_000);
+        assert_eq!(millis("2h"), 7_200_000);
+        assert_eq!(millis(" 5 s "), 5000);
+        assert_eq!(millis("0"), 0);
+    }
+
+    #[test]
+    fn bad_durations() {
+        for s in &["", "abc", "5x", "5 sec", "-5s", "1.5s", "s", "99999999999999h", "99999999999999999999"] {
+            let e = parse_duration(s).err().expect(s);
+            assert_eq!(e.to_string(), format!("Error: Bad duration: {}", s.trim()));
+        }
+    }
+
+    #[test]
+    fn checkpoints() {
+        let dir = ::std::env::temp_dir().join(format!("rabbiteer-test-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("checkpoint");
+        let file = file.to_str().unwrap();
+        let read = |f:&str| read_checkpoint(f).unwrap_or_else(|e| panic!("{}", e));
+
+        assert_eq!(read(file), None);
+        write_checkpoint(file, 42).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(read(file), Some(42));
+        write_checkpoint(file, 43).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(read(file), Some(43));
+        assert!(!Path::new(&format!("{}.tmp", file)).exists());
+
+        fs::write(file, "nope\n").unwrap();
+        let e = read_checkpoint(file).err().expect("garbage checkpoint");
+        assert_eq!(e.to_string(), format!("Error: Checkpoint {} doesn't hold an offset", file));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,81 @@
+use rustc_serialize::json::Json;
+use jsonpath;
+
+
+// check that every {{ is closed and holds a good path
+pub fn check(tpl:&str) -> Result<(),String> {
+    let mut rest = tpl;
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}")
+            .ok_or(format!("Unclosed {{{{ in template: {}", tpl))? + start;
+        jsonpath::check(&to_path(&rest[start + 2..end]))?;
+        rest = &rest[end + 2..];
+    }
+    Ok(())
+}
+
+
+// render a template such as "{{deliver.routing_key}} {{body}}" with
+// values from the message json. strings go in as they are, other
+// values as compact JSON and missing values as nothing.
+pub fn render(tpl:&str, msg:&Json) -> String {
+    let mut out = String::new();
+    let mut rest = tpl;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(e) => e + start,
+            None => break,
+        };
+        out.push_str(&rest[..start]);
+        match jsonpath::lookup(msg, &to_path(&rest[start + 2..end])) {
+            Some(&Json::String(ref s)) => out.push_str(s),
+            Some(v) => out.push_str(&v.to_string()),
+            None => (),
+        }
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+
+// deliver.routing_key -> .deliver.routing_key
+fn to_path(expr:&str) -> String {
+    let expr = expr.trim();
+    if expr.starts_with('.') {
+        expr.to_owned()
+    } else {
+        format!(".{}", expr)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg() -> Json {
+        Json::from_str(r#"{"deliver": {"routing_key": "orders.eu"},
+                           "props": {"headers": {"retry": 2}},
+                           "body": {"id": 17, "tags": ["a"]}}"#).unwrap()
+    }
+
+    #[test]
+    fn renders() {
+        let m = msg();
+        assert_eq!(render("{{deliver.routing_key}} {{body}}", &m), "orders.eu {\"id\":17,\"tags\":[\"a\"]}");
+        assert_eq!(render("{{ .body.id }}/{{props.headers.retry}}", &m), "17/2");
+        assert_eq!(render("[{{body.missing}}]", &m), "[]");
+        assert_eq!(render("{{body.tags[0]}}", &m), "a");
+        assert_eq!(render("no paths", &m), "no paths");
+        assert_eq!(render("{{body.id} left open", &m), "{{body.id} left open");
+    }
+
+    #[test]
+    fn checks() {
+        assert!(check("{{deliver.routing_key}} {{body}}").is_ok());
+        assert!(check("plain text").is_ok());
+        assert_eq!(check("{{body"), Err("Unclosed {{ in template: {{body".to_owned()));
+        assert_eq!(check("{{body..x}}"), Err("Bad path: .body..x".to_owned()));
+    }
+}
@@ -0,0 +1,260 @@
+use rustc_serialize::json::Json;
+use std::str;
+
+
+// the bare mime type, without parameters such as "; charset=utf-8"
+fn base_type(content_type:&str) -> String {
+    content_type.split(';').next().unwrap_or("").trim().to_lowercase()
+}
+
+pub fn is_json(content_type:&str) -> bool {
+    let t = base_type(content_type);
+    t == "application/json" || t.ends_with("+json")
+}
+
+pub fn is_xml(content_type:&str) -> bool {
+    let t = base_type(content_type);
+    t.ends_with("/xml") || t.ends_with("+xml")
+}
+
+
+// check that the body parses according to its content type. types
+// we don't know how to check are always ok.
+pub fn check_body(content_type:&str, body:&[u8]) -> Result<(),String> {
+    if is_json(content_type) {
+        parse_json(body).map(|_| ())
+    } else if is_xml(content_type) {
+        let text = str::from_utf8(body)
+            .map_err(|e| format!("Body is not valid UTF-8 XML: {}", e))?;
+        check_xml(text).map_err(|e| format!("Body is not valid XML: {}", e))
+    } else {
+        Ok(())
+    }
+}
+
+
+pub fn parse_json(body:&[u8]) -> Result<Json,String> {
+    let text = str::from_utf8(body)
+        .map_err(|e| format!("Body is not valid UTF-8 JSON: {}", e))?;
+    Json::from_str(text).map_err(|e| format!("Body is not valid JSON: {}", e))
+}
+
+
+// a small well-formedness check. it verifies that tags nest and
+// close properly, attributes are quoted and there is a single root.
+// DTDs are skipped over, entities are not expanded.
+fn check_xml(text:&str) -> Result<(),String> {
+
+    let mut stack:Vec<&str> = vec![];
+    let mut roots = 0;
+    let mut rest = text;
+
+    loop {
+        // text up to the next tag
+        let lt = match rest.find('<') {
+            Some(i) => i,
+            None => {
+                if stack.is_empty() && !rest.trim().is_empty() {
+                    return Err("text outside the root element".to_owned());
+                }
+                break;
+            }
+        };
+        if stack.is_empty() && !rest[..lt].trim().is_empty() {
+            return Err("text outside the root element".to_owned());
+        }
+        rest = &rest[lt..];
+
+        if rest.starts_with("<?") {
+            rest = skip_past(rest, "?>", "processing instruction")?;
+        } else if rest.starts_with("<!--") {
+            rest = skip_past(rest, "-->", "comment")?;
+        } else if rest.starts_with("<![CDATA[") {
+            if stack.is_empty() {
+                return Err("CDATA outside the root element".to_owned());
+            }
+            rest = skip_past(rest, "]]>", "CDATA section")?;
+        } else if rest.starts_with("<!") {
+            rest = skip_doctype(rest)?;
+        } else if rest.starts_with("</") {
+            let end = rest.find('>').ok_or("unclosed end tag")?;
+            let name = rest[2..end].trim();
+            match stack.pop() {
+                Some(open) if open == name => (),
+                Some(open) => return Err(format!("</{}> does not match <{}>", name, open)),
+                None => return Err(format!("unexpected </{}>", name)),
+            }
+            rest = &rest[end + 1..];
+        } else {
+            let (name, len, empty) = parse_start_tag(rest)?;
+            if stack.is_empty() {
+                roots += 1;
+                if roots > 1 {
+                    return Err(format!("second root element <{}>", name));
+                }
+            }
+            if !empty {
+                stack.push(name);
+            }
+            rest = &rest[len..];
+        }
+    }
+
+    if let Some(open) = stack.pop() {
+        return Err(format!("<{}> is never closed", open));
+    }
+    if roots == 0 {
+        return Err("no root element".to_owned());
+    }
+    Ok(())
+}
+
+fn skip_past<'a>(s:&'a str, end:&str, what:&str) -> Result<&'a str,String> {
+    s.find(end).map(|i| &s[i + end.len()..]).ok_or(format!("unclosed {}", what))
+}
+
+// <!DOCTYPE may contain an internal subset in [ ]
+fn skip_doctype(s:&str) -> Result<&str,String> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            '>' if depth == 0 => return Ok(&s[i + 1..]),
+            _ => (),
+        }
+    }
+    Err("unclosed <!DOCTYPE".to_owned())
+}
+
+// parses "<name attr="v" ...>" and returns the name, the length
+// of the tag and whether it was self closing.
+fn parse_start_tag(s:&str) -> Result<(&str, usize, bool),String> {
+    let name_end = s[1..].find(|c:char| c.is_whitespace() || c == '>' || c == '/')
+        .map(|i| i + 1)
+        .ok_or("unclosed start tag")?;
+    let name = &s[1..name_end];
+    if !is_name(name) {
+        return Err(format!("bad element name \"{}\"", name));
+    }
+
+    let mut i = name_end;
+    let bytes = s.as_bytes();
+    let mut seen:Vec<&str> = vec![];
+    loop {
+        while i < bytes.len() && (bytes[i] as char).is_whitespace() {
+            i += 1;
+        }
+        if i >= bytes.len() {
+            return Err(format!("unclosed <{}>", name));
+        }
+        if s[i..].starts_with("/>") {
+            return Ok((name, i + 2, true));
+        }
+        if bytes[i] == b'>' {
+            return Ok((name, i + 1, false));
+        }
+
+        // attribute name="value"
+        let eq = s[i..].find('=').map(|e| e + i)
+            .ok_or(format!("attribute without value in <{}>", name))?;
+        let attr = s[i..eq].trim();
+        if !is_name(attr) {
+            return Err(format!("bad attribute name \"{}\" in <{}>", attr, name));
+        }
+        if seen.contains(&attr) {
+            return Err(format!("duplicate attribute \"{}\" in <{}>", attr, name));
+        }
+        seen.push(attr);
+        i = eq + 1;
+        while i < bytes.len() && (bytes[i] as char).is_whitespace() {
+            i += 1;
+        }
+        let quote = match bytes.get(i) {
+            Some(&b'"') => '"',
+            Some(&b'\'') => '\'',
+            _ => return Err(format!("unquoted attribute \"{}\" in <{}>", attr, name)),
+        };
+        let close = s[i + 1..].find(quote)
+            .ok_or(format!("unclosed attribute \"{}\" in <{}>", attr, name))?;
+        if s[i + 1..i + 1 + close].contains('<') {
+            return Err(format!("< in attribute \"{}\" in <{}>", attr, name));
+        }
+        i = i + 1 + close + 1;
+    }
+}
+
+fn is_name(s:&str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' || c == ':' => (),
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_' || c == ':' || c == '-' || c == '.')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_types() {
+        assert!(is_json("application/json; charset=utf-8"));
+        assert!(is_json("application/vnd.api+json"));
+        assert!(is_xml("text/xml"));
+        assert!(is_xml("application/atom+xml"));
+        assert!(!is_json("text/plain"));
+    }
+
+    #[test]
+    fn json_bodies() {
+        assert!(check_body("application/json", b"{\"a\":1}").is_ok());
+        assert!(check_body("application/json", b"{\"a\":").is_err());
+        assert!(check_body("text/plain", b"{\"a\":").is_ok());
+    }
+
+    #[test]
+    fn well_formed_xml() {
+        assert!(check_xml("<a/>").is_ok());
+        assert!(check_xml("<?xml version=\"1.0\"?>\n<!DOCTYPE a [<!ELEMENT a ANY>]>\n<a><b>x</b><!-- c --></a>").is_ok());
+        assert!(check_xml("<a x=\"1\" y='2'><b z=\"3\"/></a>").is_ok());
+        assert!(check_xml("<a><![CDATA[ <not> & </tags> ]]></a>").is_ok());
+    }
+
+    #[test]
+    fn mismatched_tags() {
+        assert_eq!(check_xml("<a><b></a></b>"), Err("</a> does not match <b>".to_owned()));
+        assert_eq!(check_xml("<a></a></b>"), Err("unexpected </b>".to_owned()));
+    }
+
+    #[test]
+    fn unclosed_tags() {
+        assert_eq!(check_xml("<a><b></b>"), Err("<a> is never closed".to_owned()));
+        assert_eq!(check_xml("<a"), Err("unclosed start tag".to_owned()));
+        assert_eq!(check_xml("<a x=\"1\""), Err("unclosed <a>".to_owned()));
+        assert_eq!(check_xml("<a><!-- x</a>"), Err("unclosed comment".to_owned()));
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(check_xml("<a x=1/>"), Err("unquoted attribute \"x\" in <a>".to_owned()));
+        assert_eq!(check_xml("<a x=\"1\" x=\"2\"/>"), Err("duplicate attribute \"x\" in <a>".to_owned()));
+        assert_eq!(check_xml("<a x/>"), Err("attribute without value in <a>".to_owned()));
+        assert_eq!(check_xml("<a x=\"<\"/>"), Err("< in attribute \"x\" in <a>".to_owned()));
+        assert_eq!(check_xml("<a x=\"1/>"), Err("unclosed attribute \"x\" in <a>".to_owned()));
+    }
+
+    #[test]
+    fn cdata() {
+        assert_eq!(check_xml("<a><![CDATA[ x </a>"), Err("unclosed CDATA section".to_owned()));
+        assert_eq!(check_xml("<![CDATA[x]]><a/>"), Err("CDATA outside the root element".to_owned()));
+    }
+
+    #[test]
+    fn roots() {
+        assert_eq!(check_xml("<a/><b/>"), Err("second root element <b>".to_owned()));
+        assert_eq!(check_xml("x<a/>"), Err("text outside the root element".to_owned()));
+        assert_eq!(check_xml(""), Err("no root element".to_owned()));
+    }
+}